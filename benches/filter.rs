@@ -0,0 +1,247 @@
+// Decoding and keyword matching over a stream of firehose frames. Without a
+// capture it makes up frames: mostly posts that match nothing, some that
+// mention Toronto, and likes. To run it over real traffic, record a capture
+// with TOSKY_CAPTURE_PATH (see the README) and run
+//
+//     TOSKY_BENCH_CAPTURE=capture.bin cargo bench --bench filter
+//
+// Each frame is timed through decode_frame (CAR parsing, CBOR decoding and
+// keyword matching), with and without checking record blocks against their
+// CIDs. Commit signatures aren't checked, since that needs DID documents.
+//
+// Then each rule set is timed with the prefilter (what ingestion runs) and
+// with every post lowercased, split and evaluated in full, which is what
+// every post went through before the prefilter.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use serde_cbor::Value;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use to_sky::capture::CaptureReader;
+use to_sky::filter::{match_fields, match_post, Event};
+use to_sky::ingestion::decode_frame;
+use to_sky::matcher::{tokenize, Candidates, Matcher};
+use to_sky::models::Post;
+use to_sky::rules::Rules;
+use to_sky::verify::Verifier;
+
+/// Counts allocations, to check the prefilter's rejections are free
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// Stand-ins for the neighbourhood, venue and transit names the keyword lists
+// are growing into: 25 x 20 two word names
+const STREETS: &[&str] = &[
+    "king", "queen", "bloor", "dundas", "college", "spadina", "bathurst", "ossington", "dufferin",
+    "lansdowne", "keele", "jane", "islington", "kipling", "finch", "sheppard", "eglinton", "lawrence",
+    "wilson", "davisville", "st clair", "summerhill", "rosedale", "pape", "broadview",
+];
+const KINDS: &[&str] = &[
+    "station", "park", "village", "west", "east", "square", "market", "junction", "heights", "beach",
+    "library", "arena", "plaza", "loop", "commons", "corners", "gardens", "mills", "common", "yards",
+];
+
+/// Made up frames when there's no capture to use
+const GENERATED_FRAMES: usize = 5000;
+
+const TEXTS: &[&str] = &[
+    "just finished a 10k run, legs are done for the day",
+    "anyone else watching the game tonight? that second period was rough",
+    "new blog post: why our team moved the build to a monorepo",
+    "Streetcar short turned again at Roncesvalles, 40 minutes for the 504",
+    "the sourdough finally has a decent crumb after three weeks of trying",
+    "Patio weather in Leslieville, finally",
+    "reading the new translation of the odyssey and it's great so far",
+    "Line 1 is down between Union and St Andrew, shuttle buses running",
+    "can someone explain why my cat only sits on the keyboard during meetings",
+    "Flying out of Pearson tomorrow, how early do I need to be at the airport?",
+];
+
+fn varint(mut n: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+    out
+}
+
+fn cbor(value: serde_json::Value) -> Value {
+    serde_cbor::value::to_value(value).unwrap()
+}
+
+/// A #commit frame creating one record, with a CID that really is the
+/// record's hash so block verification passes
+fn commit_frame(seq: usize, repo: &str, path: &str, record: serde_json::Value) -> Vec<u8> {
+    let record = serde_cbor::to_vec(&cbor(record)).unwrap();
+    let cid = [&[0x01, 0x71, 0x12, 0x20][..], &Sha256::digest(&record)].concat();
+
+    let mut blocks = varint(1);
+    blocks.push(0xa0);
+    blocks.extend(varint(cid.len() + record.len()));
+    blocks.extend(&cid);
+    blocks.extend(&record);
+
+    let mut op = cbor(json!({ "action": "create", "path": path }));
+    if let Value::Map(op) = &mut op {
+        let link = [&[0x00][..], &cid].concat();
+        op.insert(Value::Text("cid".into()), Value::Tag(42, Box::new(Value::Bytes(link))));
+    }
+    let mut body = cbor(json!({ "repo": repo, "seq": seq, "time": "2025-10-09T08:53:20.000Z" }));
+    if let Value::Map(body) = &mut body {
+        body.insert(Value::Text("ops".into()), Value::Array(vec![op]));
+        body.insert(Value::Text("blocks".into()), Value::Bytes(blocks));
+    }
+
+    let mut frame = serde_cbor::to_vec(&cbor(json!({ "op": 1, "t": "#commit" }))).unwrap();
+    frame.extend(serde_cbor::to_vec(&body).unwrap());
+    frame
+}
+
+/// Three posts to every like, roughly what the firehose's posts and likes
+/// look like once the collections we ignore are left out
+fn generate_frames() -> Vec<Vec<u8>> {
+    (0..GENERATED_FRAMES).map(|i| {
+        let repo = format!("did:plc:bench{:06}", i % 997);
+        if i % 4 == 3 {
+            let subject = format!("at://did:plc:bench{:06}/app.bsky.feed.post/3m2q{:08}", (i + 1) % 997, i - 1);
+            return commit_frame(i, &repo, &format!("app.bsky.feed.like/3m2q{:08}", i), json!({
+                "$type": "app.bsky.feed.like",
+                "createdAt": "2025-10-09T08:53:20.000Z",
+                "subject": { "uri": subject, "cid": "bafyreigks6arfsq3xxfpvqrrwonchxcnu6do76auprhhfomao6c273sixm" },
+            }));
+        }
+        commit_frame(i, &repo, &format!("app.bsky.feed.post/3m2q{:08}", i), json!({
+            "$type": "app.bsky.feed.post",
+            "createdAt": "2025-10-09T08:53:20.000Z",
+            "langs": ["en"],
+            "text": TEXTS[i % TEXTS.len()],
+        }))
+    }).collect()
+}
+
+/// Frames from TOSKY_BENCH_CAPTURE if it's set, otherwise made up ones
+fn load_frames() -> Option<Vec<Vec<u8>>> {
+    let Ok(path) = std::env::var("TOSKY_BENCH_CAPTURE") else {
+        println!("Using {} generated frames, set TOSKY_BENCH_CAPTURE to use a capture file", GENERATED_FRAMES);
+        return Some(generate_frames());
+    };
+
+    match CaptureReader::open(&path) {
+        Ok(reader) => Some(reader.map_while(Result::ok).map(|(_, data)| data).collect()),
+        Err(e) => {
+            eprintln!("Could not open {}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn decode_posts(frames: &[Vec<u8>]) -> Vec<Post> {
+    let verifier = Verifier::new(false);
+    frames.iter()
+        .flat_map(|data| decode_frame(&verifier, data).events)
+        .filter_map(|event| match event {
+            Event::Post { post, .. } => Some(*post),
+            _ => None,
+        })
+        .collect()
+}
+
+fn rule_sets() -> Vec<(&'static str, Rules)> {
+    let builtin = include_str!("../rules.json");
+
+    let mut large: serde_json::Value = serde_json::from_str(builtin).unwrap();
+    let keywords = large["strict_keywords"].as_array_mut().unwrap();
+    for street in STREETS {
+        for kind in KINDS {
+            keywords.push(format!("{} {}", street, kind).into());
+        }
+    }
+
+    vec![
+        ("built-in rules", Rules::parse(builtin).unwrap()),
+        ("500 more keywords", Rules::parse(&large.to_string()).unwrap()),
+    ]
+}
+
+/// Panics if a post the prefilter turns away allocated anything
+fn check_allocations(name: &str, matcher: &Matcher, posts: &[Post]) {
+    let mut rejected = 0;
+    for post in posts {
+        let mut candidates = Candidates::default();
+        for (_, text) in match_fields(post) {
+            matcher.scan(&text, &mut candidates);
+        }
+        if !candidates.is_empty() {
+            continue;
+        }
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        black_box(match_post(matcher, post));
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        assert_eq!(allocations, 0, "{}: a post the prefilter rejected made {} allocations", name, allocations);
+        rejected += 1;
+    }
+
+    let matched = posts.iter().filter(|post| match_post(matcher, post).is_some()).count();
+    println!("{}: {} posts, {} matched, {} rejected by the prefilter without allocating",
+        name, posts.len(), matched, rejected);
+}
+
+fn frame_decoding(c: &mut Criterion, frames: &[Vec<u8>]) {
+    let mut group = c.benchmark_group("per frame");
+    group.throughput(Throughput::Elements(frames.len() as u64));
+    for (name, verify_blocks) in [("decode and match", false), ("decode, verify blocks and match", true)] {
+        let verifier = Verifier::new(verify_blocks);
+        group.bench_function(name, |b| b.iter(|| {
+            frames.iter().map(|data| decode_frame(&verifier, data).events.len()).sum::<usize>()
+        }));
+        let mismatches = verifier.blocks.mismatches.load(Ordering::Relaxed);
+        assert_eq!(mismatches, 0, "{}: {} blocks failed verification", name, mismatches);
+    }
+    group.finish();
+}
+
+fn keyword_matching(c: &mut Criterion, posts: &[Post]) {
+    for (name, rules) in rule_sets() {
+        check_allocations(name, &rules.matcher, posts);
+
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Elements(posts.len() as u64));
+        group.bench_function("prefilter", |b| b.iter(|| {
+            posts.iter().filter(|post| match_post(&rules.matcher, post).is_some()).count()
+        }));
+        group.bench_function("full match", |b| b.iter(|| {
+            posts.iter().filter(|post| rules.matcher.matches(&tokenize(&match_fields(post)), &Candidates::all()).next().is_some()).count()
+        }));
+        group.finish();
+    }
+}
+
+fn filter(c: &mut Criterion) {
+    let Some(frames) = load_frames() else { return };
+    frame_decoding(c, &frames);
+    keyword_matching(c, &decode_posts(&frames));
+}
+
+criterion_group!(benches, filter);
+criterion_main!(benches);
@@ -0,0 +1,96 @@
+// Ingestion's DB writes, applied the way the ingestion thread batches them
+// and one transaction per write, which is what every post and like cost
+// before batching. Uses a fresh database file for every run:
+//
+//     cargo bench --bench writes
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::path::{Path, PathBuf};
+use to_sky::db::{Database, Write};
+use to_sky::models::{InteractionType, MatchReason, TorontoPost};
+
+const POSTS: usize = 200;
+const LIKES_PER_POST: usize = 9;
+/// Same as the ingestion thread's BATCH_MAX_WRITES
+const BATCH: usize = 1000;
+
+/// A busy stretch of firehose: new posts, each followed by likes on it
+fn writes() -> Vec<Write> {
+    let mut writes = Vec::new();
+    for i in 0..POSTS {
+        let uri = format!("at://did:plc:author{}/app.bsky.feed.post/{}", i % 20, i);
+        writes.push(Write::InsertPost(TorontoPost {
+            uri: uri.clone(),
+            cid: format!("bafyreipost{}", i),
+            did: format!("did:plc:author{}", i % 20),
+            indexed_at: 1_700_000_000 + i as i64,
+            created_at: 1_700_000_000 + i as i64,
+            reply_parent: None,
+            reply_root: None,
+            reason: MatchReason { rules: vec!["toronto".to_string()], field: "text".to_string(), snippet: "in toronto".to_string() },
+        }));
+        for j in 0..LIKES_PER_POST {
+            writes.push(Write::Interaction {
+                actor_did: format!("did:plc:fan{}", j),
+                kind: InteractionType::LIKE,
+                rkey: format!("{}-{}", i, j),
+                subject_uri: uri.clone(),
+            });
+        }
+    }
+    writes
+}
+
+/// An empty database in a file, so commits cost what they do in production.
+/// Removed when dropped, which criterion does outside the timed part.
+struct TempDb {
+    db: Database,
+    path: PathBuf,
+}
+
+impl TempDb {
+    fn new(run: &mut usize) -> Self {
+        *run += 1;
+        let path = std::env::temp_dir().join(format!("tosky-bench-writes-{}-{}.db", std::process::id(), run));
+        remove(&path);
+        TempDb { db: Database::new(path.to_str().unwrap()), path }
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        remove(&self.path);
+    }
+}
+
+fn remove(path: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+    }
+}
+
+fn ingestion_writes(c: &mut Criterion) {
+    let writes = writes();
+    let mut run = 0;
+
+    let mut group = c.benchmark_group("ingestion writes");
+    group.throughput(Throughput::Elements(writes.len() as u64));
+    group.sample_size(10);
+    for (name, batch) in [("one transaction per write", 1), ("batched", BATCH)] {
+        group.bench_function(name, |b| b.iter_batched(
+            || TempDb::new(&mut run),
+            |mut temp| {
+                for chunk in writes.chunks(batch) {
+                    temp.db.apply_writes(chunk, None).unwrap();
+                }
+                assert_eq!(temp.db.dropped_writes(), 0);
+                temp
+            },
+            BatchSize::PerIteration,
+        ));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ingestion_writes);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+#![no_main]
+
+//! Throws arbitrary bytes at everything that decodes untrusted firehose data.
+//! Any panic is a bug, errors are expected. Run with
+//! `cargo +nightly fuzz run parse_frame` from the repo root.
+
+use libfuzzer_sys::fuzz_target;
+use to_sky::models::FirehoseMessage;
+use to_sky::parser::{cbor_item_len, parse_car_blocks, parse_message, read_varint};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_car_blocks(data);
+    let _ = read_varint(data);
+    let _ = cbor_item_len(data);
+
+    // A frame that decodes gets its CAR parsed too, like ingestion does
+    if let Ok(Some(FirehoseMessage::Commit(frame))) = parse_message(data) {
+        let _ = parse_car_blocks(&frame.blocks);
+    }
+});
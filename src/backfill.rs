@@ -1,9 +1,11 @@
 use crate::db::Database;
-use crate::filter::{LAX_KEYWORDS, STRICT_KEYWORDS, NSFW_LABELS};
+use crate::filter::{excluded_by, matches_keywords, Inclusion};
+use crate::models::{MatchReason, Post, TorontoPost};
+use crate::rules;
 use serde_json::Value;
 use std::process::Command;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 struct TimeWindow {
     label: &'static str,
@@ -21,6 +23,7 @@ const WINDOWS: &[TimeWindow] = &[
 
 const MAX_RETRIES: u32 = 5;
 const BASE_SLEEP_MS: u64 = 1000;
+const SEARCH_SNIPPET_CHARS: usize = 80;
 
 // Note: lowercase "searchposts" is intentional — the CDN blocks the cursor
 // parameter on the canonical "searchPosts" endpoint (known issue:
@@ -31,6 +34,7 @@ pub fn run_backfill(db: &mut Database) {
     println!("[Backfill] Starting reverse hydration via search API");
 
     let now = chrono::Utc::now();
+    let rules = rules::current();
     let mut total_inserted = 0;
     let mut total_queries = 0;
 
@@ -47,7 +51,7 @@ pub fn run_backfill(db: &mut Database) {
         let mut window_inserted = 0;
 
         // Lax keywords: subdivide into 1-hour chunks (high volume)
-        for keyword in LAX_KEYWORDS {
+        for keyword in &rules.lax_keywords {
             let window_hours = window.hours_ago_end - window.hours_ago_start;
             for chunk in 0..window_hours {
                 let chunk_start = window_start + chrono::Duration::hours(chunk);
@@ -65,7 +69,7 @@ pub fn run_backfill(db: &mut Database) {
         }
 
         // Strict keywords: use full window (low volume)
-        for keyword in STRICT_KEYWORDS {
+        for keyword in &rules.strict_keywords {
             let since = window_start.format("%Y-%m-%dT%H:%M:%SZ").to_string();
             let until = window_end.format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
@@ -103,7 +107,7 @@ fn search_all_pages(db: &mut Database, keyword: &str, since: &str, until: &str)
 
         let page_count = posts.len();
         total_results += page_count;
-        total_inserted += insert_posts(db, posts);
+        total_inserted += insert_posts(db, posts, keyword);
 
         cursor = json["cursor"].as_str()
             .filter(|s| !s.is_empty())
@@ -179,14 +183,15 @@ fn fetch_page_with_retry(keyword: &str, since: &str, until: &str, cursor: Option
     None
 }
 
-fn insert_posts(db: &mut Database, posts: &[Value]) -> i64 {
+fn insert_posts(db: &mut Database, posts: &[Value], keyword: &str) -> i64 {
     let mut inserted = 0i64;
+    let rules = rules::current();
 
     for post in posts {
         if let Some(labels) = post["labels"].as_array() {
             let is_nsfw = labels.iter().any(|l| {
                 let val = l["val"].as_str().unwrap_or("");
-                NSFW_LABELS.contains(&val)
+                rules.nsfw_labels.iter().any(|label| label == val)
             });
             if is_nsfw {
                 continue;
@@ -206,6 +211,26 @@ fn insert_posts(db: &mut Database, posts: &[Value]) -> i64 {
             None => continue,
         };
 
+        let record = serde_json::from_value::<Post>(post["record"].clone()).ok();
+
+        // Search only found a keyword, the exclusions still have to be checked
+        if let Some(rule) = record.as_ref().and_then(|record| excluded_by(did, record)) {
+            println!("[Backfill] Suppressed {} by exclusion \"{}\"", uri, rule);
+            db.record_suppression(&rule, uri);
+            continue;
+        }
+
+        // Search also matches things the rules don't (stemming, the author's
+        // handle), and then the search term is all we can say
+        let reason = match record.as_ref().and_then(matches_keywords) {
+            Some(keyword_match) => Inclusion::Keyword(keyword_match).into(),
+            None => MatchReason {
+                rules: vec![keyword.to_string()],
+                field: "search".to_string(),
+                snippet: record.map(|record| record.text.chars().take(SEARCH_SNIPPET_CHARS).collect()).unwrap_or_default(),
+            },
+        };
+
         let created_at = post["record"]["createdAt"]
             .as_str()
             .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
@@ -217,12 +242,63 @@ fn insert_posts(db: &mut Database, posts: &[Value]) -> i64 {
         let quotes = post["quoteCount"].as_i64().unwrap_or(0);
         let replies = post["replyCount"].as_i64().unwrap_or(0);
 
-        if let Err(e) = db.insert_post_if_not_exists(uri, cid, did, created_at, likes, reposts, quotes, replies) {
+        let toronto_post = TorontoPost {
+            uri: uri.to_string(),
+            cid: cid.to_string(),
+            did: did.to_string(),
+            indexed_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            created_at,
+            reply_parent: post["record"]["reply"]["parent"]["uri"].as_str().map(String::from),
+            reply_root: post["record"]["reply"]["root"]["uri"].as_str().map(String::from),
+            reason,
+        };
+
+        if let Err(e) = db.insert_post_if_not_exists(&toronto_post, likes, reposts, quotes, replies) {
             eprintln!("[Backfill] Insert error: {}", e);
         } else {
             inserted += 1;
         }
+
+        if let Some(handle) = post["author"]["handle"].as_str() {
+            db.set_handle(did, Some(handle));
+        }
     }
 
     inserted
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A post as the search API returns it
+    fn search_result(uri: &str, text: &str) -> Value {
+        let did = uri.strip_prefix("at://").unwrap().split('/').next().unwrap();
+        json!({
+            "uri": uri,
+            "cid": "bafyreigks6arfsq3xxfpvqrrwonchxcnu6do76auprhhfomao6c273sixm",
+            "author": { "did": did, "handle": "someone.bsky.social" },
+            "record": { "$type": "app.bsky.feed.post", "text": text, "createdAt": "2025-10-09T08:53:20.000Z" },
+            "likeCount": 3,
+        })
+    }
+
+    #[test]
+    fn checks_exclusions() {
+        // Uses the built-in rules.json, which excludes "crypto spam"
+        let mut db = Database::new(":memory:");
+        let spam = "at://did:plc:spam/app.bsky.feed.post/1";
+        let fine = "at://did:plc:someone/app.bsky.feed.post/1";
+        let posts = [search_result(spam, "Toronto airdrop, claim your tokens"), search_result(fine, "Patio season in Toronto")];
+
+        assert_eq!(insert_posts(&mut db, &posts, "toronto"), 1);
+        assert!(!db.has_post(spam));
+        assert!(db.has_post(fine));
+        assert_eq!(db.get_suppressions().get("crypto spam"), Some(&(1, spam.to_string())));
+
+        // Found again by the next search term
+        insert_posts(&mut db, &posts[..1], "6ix");
+        assert_eq!(db.get_suppressions().get("crypto spam"), Some(&(2, spam.to_string())));
+    }
+}
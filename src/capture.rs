@@ -0,0 +1,241 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Capture file format: a flat sequence of records, each one
+// [received_at_ms: u64 BE][len: u32 BE][len bytes of the raw WebSocket binary frame]
+//
+// A crash can leave the last record half written. The writer cuts such a
+// record off before appending, and the reader reports it as an error rather
+// than a clean end of file.
+
+const HEADER_BYTES: u64 = 12;
+
+/// Far past any frame a relay sends, so a corrupt length can't make the
+/// reader allocate gigabytes
+pub const MAX_FRAME_BYTES: u32 = 16 * 1024 * 1024;
+
+pub struct CaptureWriter {
+    out: BufWriter<File>,
+}
+
+impl CaptureWriter {
+    /// Appends to an existing capture so restarts don't clobber earlier
+    /// traffic. Returns how many bytes of a torn last record were cut off
+    /// first. Fails if the file is damaged anywhere else.
+    pub fn open(path: &str) -> io::Result<(Self, u64)> {
+        let mut file = OpenOptions::new().create(true).read(true).write(true).truncate(false).open(path)?;
+        let len = file.metadata()?.len();
+        let complete = complete_len(&mut file, len)?;
+        if complete < len {
+            file.set_len(complete)?;
+        }
+        file = OpenOptions::new().append(true).open(path)?;
+        Ok((CaptureWriter { out: BufWriter::new(file) }, len - complete))
+    }
+
+    /// If this fails the file may end in a partial record, so stop writing
+    /// to it. The next open cuts that record off.
+    pub fn write_frame(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(data.len()).ok().filter(|&len| len <= MAX_FRAME_BYTES)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} byte frame is too big to record", data.len())))?;
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        self.out.write_all(&now_ms.to_be_bytes())?;
+        self.out.write_all(&len.to_be_bytes())?;
+        self.out.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Bytes of the file taken up by whole records, which is less than len if
+/// the last one is torn
+fn complete_len(file: &mut File, len: u64) -> io::Result<u64> {
+    let mut input = BufReader::new(file);
+    let mut end = 0;
+    while end + HEADER_BYTES <= len {
+        let mut header = [0u8; HEADER_BYTES as usize];
+        input.read_exact(&mut header)?;
+        let frame_len = u32::from_be_bytes(header[8..].try_into().unwrap());
+        if frame_len > MAX_FRAME_BYTES {
+            return Err(bad_length(frame_len));
+        }
+        if end + HEADER_BYTES + frame_len as u64 > len {
+            break;
+        }
+        input.seek_relative(frame_len as i64)?;
+        end += HEADER_BYTES + frame_len as u64;
+    }
+    Ok(end)
+}
+
+fn bad_length(len: u32) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("record claims {} bytes, capture is corrupt", len))
+}
+
+pub struct CaptureReader {
+    input: BufReader<File>,
+    /// Nothing after a bad record can be trusted, so the first error ends it
+    failed: bool,
+}
+
+impl CaptureReader {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Ok(CaptureReader { input: BufReader::new(File::open(path)?), failed: false })
+    }
+
+    fn read_record(&mut self) -> Option<io::Result<(u64, Vec<u8>)>> {
+        // Only the end of the file between records is a clean end
+        match self.input.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => {},
+            Err(e) => return Some(Err(e)),
+        }
+
+        let mut ts = [0u8; 8];
+        if let Err(e) = self.input.read_exact(&mut ts) {
+            return Some(Err(torn_if_eof(e)));
+        }
+
+        let mut len = [0u8; 4];
+        if let Err(e) = self.input.read_exact(&mut len) {
+            return Some(Err(torn_if_eof(e)));
+        }
+        let len = u32::from_be_bytes(len);
+        if len > MAX_FRAME_BYTES {
+            return Some(Err(bad_length(len)));
+        }
+
+        let mut data = vec![0u8; len as usize];
+        if let Err(e) = self.input.read_exact(&mut data) {
+            return Some(Err(torn_if_eof(e)));
+        }
+
+        Some(Ok((u64::from_be_bytes(ts), data)))
+    }
+}
+
+impl Iterator for CaptureReader {
+    /// (received_at_ms, frame bytes)
+    type Item = io::Result<(u64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let record = self.read_record();
+        self.failed = matches!(record, Some(Err(_)));
+        record
+    }
+}
+
+fn torn_if_eof(e: io::Error) -> io::Error {
+    if e.kind() != io::ErrorKind::UnexpectedEof {
+        return e;
+    }
+    io::Error::new(io::ErrorKind::UnexpectedEof, "capture ends partway through a record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Event;
+    use crate::ingestion::{decode_frame, FrameOutcome};
+    use crate::verify::Verifier;
+
+    /// The events in tests/fixtures/jetstream.jsonl as the frames a relay
+    /// sends for them
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/firehose.capture");
+
+    fn temp_capture(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("tosky-capture-{}-{}.bin", std::process::id(), name));
+        std::fs::remove_file(&path).ok();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn write(path: &str, frames: &[&[u8]]) -> u64 {
+        let (mut writer, torn) = CaptureWriter::open(path).unwrap();
+        for frame in frames {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.flush().unwrap();
+        torn
+    }
+
+    fn read(path: &str) -> Vec<io::Result<Vec<u8>>> {
+        CaptureReader::open(path).unwrap().map(|record| record.map(|(_, data)| data)).collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let path = temp_capture("round-trip");
+        assert_eq!(write(&path, &[b"one", b"", b"three"]), 0);
+        // Reopening appends
+        assert_eq!(write(&path, &[b"four"]), 0);
+
+        let frames: Vec<Vec<u8>> = read(&path).into_iter().map(Result::unwrap).collect();
+        assert_eq!(frames, [&b"one"[..], b"", b"three", b"four"]);
+    }
+
+    #[test]
+    fn torn_record_is_an_error_and_cut_off_before_appending() {
+        let path = temp_capture("torn");
+        write(&path, &[b"one", b"two"]);
+        let whole = std::fs::metadata(&path).unwrap().len();
+
+        // Torn in the timestamp, the length and the frame itself
+        for cut in [whole - 5 - 8, whole - 5 - 2, whole - 1] {
+            let file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_len(cut).unwrap();
+
+            let records = read(&path);
+            assert_eq!(records.len(), 2, "cut at {}", cut);
+            assert_eq!(records[0].as_ref().unwrap(), b"one");
+            assert_eq!(records[1].as_ref().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+            assert_eq!(write(&path, &[b"two"]), cut - (whole - 15));
+            let frames: Vec<Vec<u8>> = read(&path).into_iter().map(Result::unwrap).collect();
+            assert_eq!(frames, [b"one", b"two"]);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_records() {
+        let path = temp_capture("oversized");
+        let mut record = 0u64.to_be_bytes().to_vec();
+        record.extend((MAX_FRAME_BYTES + 1).to_be_bytes());
+        record.extend(b"not that long");
+        std::fs::write(&path, &record).unwrap();
+
+        let records = read(&path);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].as_ref().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Corruption before the end isn't something to quietly cut off
+        assert_eq!(CaptureWriter::open(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        let (mut writer, _) = CaptureWriter::open(&temp_capture("oversized-write")).unwrap();
+        let too_big = vec![0; MAX_FRAME_BYTES as usize + 1];
+        assert_eq!(writer.write_frame(&too_big).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn fixture_decodes() {
+        let verifier = Verifier::from_env();
+        let mut posts = 0;
+        let mut events = 0;
+        for record in CaptureReader::open(FIXTURE).unwrap() {
+            let (_, data) = record.unwrap();
+            let decoded = decode_frame(&verifier, &data);
+            assert!(matches!(decoded.outcome, FrameOutcome::Processed { .. }));
+            posts += decoded.events.iter().filter(|event| matches!(event, Event::Post { .. })).count();
+            events += decoded.events.len();
+        }
+        assert_eq!((events, posts), (11, 4));
+    }
+}
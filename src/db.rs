@@ -1,5 +1,6 @@
 use sqlite::{Connection, State};
-use crate::models::{Post, TorontoPost};
+use crate::models::{Post, TorontoPost, MatchReason, InteractionType, Cid};
+use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // Ranking parameters
@@ -8,9 +9,39 @@ const DECAY_RATE: f64 = 0.05;     // Quadratic decay factor (age^2 * this)
 const SHUFFLE_MOD: i32 = 5;      // Range of hourly shuffle (0 to N-1)
 const SHUFFLE_MULT: i32 = 7;      // Multiplier for URI-based variance
 
+// Must stay in sync with the score computed in update_engagement
+const SCORE_FORMULA: &str = "likes + reposts * 2 + quotes * 3 + replies + bookmarks";
+
+// Multiplies a post's ranking by how it matched. Hashtags and the post's own
+// tags are deliberate, a keyword inside a URL is often incidental. Posts from
+// before match_field was recorded get 1.0.
+const MATCH_WEIGHT: &str = "CASE match_field
+    WHEN 'hashtag' THEN 1.5
+    WHEN 'tag' THEN 1.5
+    WHEN 'image_alt' THEN 0.9
+    WHEN 'video_alt' THEN 0.9
+    WHEN 'card_title' THEN 0.9
+    WHEN 'card_description' THEN 0.9
+    WHEN 'quote' THEN 0.8
+    WHEN 'reply' THEN 0.8
+    WHEN 'link' THEN 0.7
+    WHEN 'card_url' THEN 0.7
+    ELSE 1.0 END";
+
+// Likes/reposts from accounts that have posts in the feed themselves count extra
+const LOCAL_ENGAGEMENT_WEIGHT: i64 = 2;
+const LOCAL_ENGAGEMENT: &str = "(SELECT COUNT(*) FROM interactions i
+    WHERE i.subject_uri = posts.uri AND i.actor_did IN (SELECT did FROM posts))";
+
+// Shared by ingestion's batched writes and backfill
+const RECORD_SUPPRESSION: &str = "
+    INSERT INTO suppressions (rule, count, last_uri, last_at) VALUES (?, 1, ?, ?)
+    ON CONFLICT (rule) DO UPDATE SET count = count + 1, last_uri = excluded.last_uri, last_at = excluded.last_at";
+
 pub struct Database {
     conn: Connection,
-    counter: i32
+    counter: i32,
+    dropped_writes: u64,
 }
 
 pub enum Column {
@@ -18,6 +49,33 @@ pub enum Column {
     Reposts,
 }
 
+impl Column {
+    fn for_interaction(kind: &InteractionType) -> Column {
+        match kind {
+            InteractionType::LIKE => Column::Likes,
+            InteractionType::REPOST => Column::Reposts,
+        }
+    }
+}
+
+/// A write produced by ingestion. These are queued up and applied in
+/// size/time bounded transactions by apply_writes.
+pub enum Write {
+    InsertPost(TorontoPost),
+    DeletePost(String),
+    /// Every post by an account, e.g. after a takedown
+    DeletePostsByDid(String),
+    SetHandle { did: String, handle: Option<String> },
+    Interaction { actor_did: String, kind: InteractionType, rkey: String, subject_uri: String },
+    InteractionDeleted { actor_did: String, kind: InteractionType, rkey: String },
+    /// A reply, which only counts if its parent is in the feed
+    Reply { uri: String, parent_uri: String },
+    /// A quote post, which only counts if the quoted post is in the feed
+    Quote { uri: String, subject_uri: String },
+    /// A post an exclusion rule kept out of the feed
+    Suppressed { rule: String, uri: String },
+}
+
 pub struct Metadata {
     pub seq: i64,
     pub last_updated: i64,
@@ -28,6 +86,8 @@ impl Database {
         let conn = sqlite::open(path).unwrap();
         conn.execute("PRAGMA journal_mode=WAL;").ok();
         conn.execute("PRAGMA busy_timeout=5000;").ok();
+        // WAL + NORMAL is still atomic per transaction, it just skips the fsync on every commit
+        conn.execute("PRAGMA synchronous=NORMAL;").ok();
 
         let q = "
             CREATE TABLE IF NOT EXISTS posts (
@@ -44,6 +104,55 @@ impl Database {
                 last_enriched INTEGER DEFAULT 0
             );
 
+            -- Remembers which like/repost record pointed at which post, since
+            -- delete ops only carry the record path and no body
+            CREATE TABLE IF NOT EXISTS interactions (
+                actor_did TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                rkey TEXT NOT NULL,
+                subject_uri TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (actor_did, kind, rkey)
+            );
+
+            CREATE INDEX IF NOT EXISTS interactions_subject ON interactions (subject_uri);
+
+            -- Replies to posts in the feed, so a deleted reply can come back off
+            -- its parent's count
+            CREATE TABLE IF NOT EXISTS replies (
+                uri TEXT PRIMARY KEY,
+                parent_uri TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS replies_parent ON replies (parent_uri);
+
+            -- Same for quote posts of posts in the feed
+            CREATE TABLE IF NOT EXISTS quotes (
+                uri TEXT PRIMARY KEY,
+                subject_uri TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS quotes_subject ON quotes (subject_uri);
+            CREATE INDEX IF NOT EXISTS posts_did ON posts (did);
+
+            -- How many posts each exclusion rule has kept out of the feed, and
+            -- the latest one, for tuning the rules
+            CREATE TABLE IF NOT EXISTS suppressions (
+                rule TEXT PRIMARY KEY,
+                count INTEGER NOT NULL,
+                last_uri TEXT NOT NULL,
+                last_at INTEGER NOT NULL
+            );
+
+            -- Last known handle for authors in the feed
+            CREATE TABLE IF NOT EXISTS handles (
+                did TEXT PRIMARY KEY,
+                handle TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+
             CREATE TABLE IF NOT EXISTS metadata (
                 key TEXT PRIMARY KEY,
                 value TEXT NOT NULL
@@ -57,26 +166,270 @@ impl Database {
         // Migration: add created_at column if it doesn't exist
         conn.execute("ALTER TABLE posts ADD COLUMN created_at INTEGER DEFAULT 0").ok();
 
-        Database { conn, counter: 0 }
+        // Migration: thread info for replies, NULL for top level posts
+        conn.execute("ALTER TABLE posts ADD COLUMN reply_parent TEXT").ok();
+        conn.execute("ALTER TABLE posts ADD COLUMN reply_root TEXT").ok();
+
+        // Migration: why each post matched, see MatchReason. NULL for older posts.
+        conn.execute("ALTER TABLE posts ADD COLUMN match_rules TEXT").ok();
+        conn.execute("ALTER TABLE posts ADD COLUMN match_field TEXT").ok();
+        conn.execute("ALTER TABLE posts ADD COLUMN match_snippet TEXT").ok();
+
+        // Migration: enrichment runs in a row that getPosts left a post out of
+        conn.execute("ALTER TABLE posts ADD COLUMN enrich_misses INTEGER DEFAULT 0").ok();
+
+        // Migration: cursors are per relay now, the old single cursor was bsky.network's
+        conn.execute("
+            UPDATE OR IGNORE metadata SET key = 'cursor:bsky.network' WHERE key = 'cursor';
+            UPDATE OR IGNORE metadata SET key = 'cursor:bsky.network_updated' WHERE key = 'cursor_updated';
+        ").ok();
+
+        // Migration: one interaction per (post, actor, kind) so replays and repeat
+        // likes from the same account don't double count
+        let q = "
+            DELETE FROM interactions WHERE rowid NOT IN (
+                SELECT MIN(rowid) FROM interactions GROUP BY subject_uri, actor_did, kind
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS interactions_actor
+                ON interactions (subject_uri, actor_did, kind);
+        ";
+        if let Err(e) = conn.execute(q) {
+            eprintln!("There was an error deduplicating interactions {}", e);
+        }
+
+        // Migration: firehose posts used to store the CID as raw hex, rewrite
+        // those rows in the same base32 form the API gives us
+        if let Err(e) = normalize_hex_cids(&conn) {
+            eprintln!("There was an error normalizing post CIDs {}", e);
+        }
+
+        Database { conn, counter: 0, dropped_writes: 0 }
     }
 
-    pub fn insert_post(&mut self, post: &TorontoPost) -> Result<(), Box<dyn std::error::Error>> {
+    /// Applies queued ingestion writes, plus the cursor they lead up to, in one
+    /// transaction. Statements are prepared once per batch and reused.
+    /// A write that fails is rolled back on its own, logged and counted in
+    /// dropped_writes. If the transaction can't be started or committed
+    /// nothing is written, cursor included, and the caller can retry the same
+    /// writes.
+    pub fn apply_writes(&mut self, writes: &[Write], cursor: Option<(&str, &Metadata)>) -> Result<(), Box<dyn std::error::Error>> {
+        self.begin()?;
+
         if self.counter >= 1000 {
             self.pop_posts();
         }
 
-        let mut stmt = self.conn.prepare("INSERT INTO posts (uri, cid, did, indexed_at, created_at) VALUES (?, ?, ?, ?, ?)")?;
-        stmt.bind((1, post.uri.as_str()))?;
-        stmt.bind((2, post.cid.as_str()))?;
-        stmt.bind((3, post.did.as_str()))?;
-        stmt.bind((4, post.indexed_at))?;
-        stmt.bind((5, post.created_at))?;
-        stmt.next()?;
+        let (inserted, dropped) = match self.apply_in_transaction(writes) {
+            Ok(counts) => counts,
+            Err(e) => {
+                self.conn.execute("ROLLBACK").ok();
+                return Err(e);
+            }
+        };
 
-        self.counter += 1;
+        if let Some((key, metadata)) = cursor {
+            self.set_metadata(key, metadata);
+        }
+
+        self.commit()?;
+        self.counter += inserted;
+        self.dropped_writes += dropped;
         Ok(())
     }
 
+    /// Writes apply_writes has skipped because they failed, since startup
+    pub fn dropped_writes(&self) -> u64 {
+        self.dropped_writes
+    }
+
+    /// Returns how many posts were inserted and how many writes were dropped
+    fn apply_in_transaction(&self, writes: &[Write]) -> Result<(i32, u64), Box<dyn std::error::Error>> {
+        // Posts redelivered after a reconnect or crash are already in, replays
+        // from the stored cursor leave them alone
+        let mut insert_post = self.conn.prepare(
+            "INSERT OR IGNORE INTO posts (uri, cid, did, indexed_at, created_at, reply_parent, reply_root, match_rules, match_field, match_snippet)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )?;
+        let mut delete_post = self.conn.prepare("DELETE FROM posts WHERE uri = ?")?;
+        let mut delete_posts_by_did = self.conn.prepare("DELETE FROM posts WHERE did = ?")?;
+        let mut delete_post_interactions = self.conn.prepare("DELETE FROM interactions WHERE subject_uri = ?")?;
+        let mut delete_post_replies = self.conn.prepare("DELETE FROM replies WHERE parent_uri = ?")?;
+        let mut delete_post_quotes = self.conn.prepare("DELETE FROM quotes WHERE subject_uri = ?")?;
+        let mut upsert_handle = self.conn.prepare(
+            "INSERT OR REPLACE INTO handles (did, handle, updated_at)
+             SELECT ?, ?, ? WHERE EXISTS (SELECT 1 FROM posts WHERE did = ?)"
+        )?;
+        let mut delete_handle = self.conn.prepare("DELETE FROM handles WHERE did = ?")?;
+        // Only posts in the feed are tracked, and only once per (post, actor, kind)
+        let mut record_interaction = self.conn.prepare(
+            "INSERT OR IGNORE INTO interactions (actor_did, kind, rkey, subject_uri, created_at)
+             SELECT ?, ?, ?, ?, ? WHERE EXISTS (SELECT 1 FROM posts WHERE uri = ?)"
+        )?;
+        let mut find_interaction = self.conn.prepare(
+            "SELECT subject_uri FROM interactions WHERE actor_did = ? AND kind = ? AND rkey = ?"
+        )?;
+        let mut delete_interaction = self.conn.prepare(
+            "DELETE FROM interactions WHERE actor_did = ? AND kind = ? AND rkey = ?"
+        )?;
+        let mut record_reply = self.conn.prepare(
+            "INSERT OR IGNORE INTO replies (uri, parent_uri, created_at)
+             SELECT ?, ?, ? WHERE EXISTS (SELECT 1 FROM posts WHERE uri = ?)"
+        )?;
+        let mut find_reply = self.conn.prepare("SELECT parent_uri FROM replies WHERE uri = ?")?;
+        let mut delete_reply = self.conn.prepare("DELETE FROM replies WHERE uri = ?")?;
+        let mut record_quote = self.conn.prepare(
+            "INSERT OR IGNORE INTO quotes (uri, subject_uri, created_at)
+             SELECT ?, ?, ? WHERE EXISTS (SELECT 1 FROM posts WHERE uri = ?)"
+        )?;
+        let mut find_quote = self.conn.prepare("SELECT subject_uri FROM quotes WHERE uri = ?")?;
+        let mut delete_quote = self.conn.prepare("DELETE FROM quotes WHERE uri = ?")?;
+        let mut record_suppression = self.conn.prepare(RECORD_SUPPRESSION)?;
+        // Never lets a count go below zero
+        let mut adjust_likes = self.conn.prepare("UPDATE posts SET likes = MAX(likes + ?, 0) WHERE uri = ?")?;
+        let mut adjust_reposts = self.conn.prepare("UPDATE posts SET reposts = MAX(reposts + ?, 0) WHERE uri = ?")?;
+        let mut adjust_replies = self.conn.prepare("UPDATE posts SET replies = MAX(replies + ?, 0) WHERE uri = ?")?;
+        let mut adjust_quotes = self.conn.prepare("UPDATE posts SET quotes = MAX(quotes + ?, 0) WHERE uri = ?")?;
+        let mut refresh_score = self.conn.prepare(format!(
+            "UPDATE posts SET score = {} + {} * {} WHERE uri = ?",
+            SCORE_FORMULA, LOCAL_ENGAGEMENT, LOCAL_ENGAGEMENT_WEIGHT
+        ))?;
+        let mut savepoint = self.conn.prepare("SAVEPOINT write")?;
+        let mut release = self.conn.prepare("RELEASE write")?;
+        let mut rollback_to = self.conn.prepare("ROLLBACK TO write")?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut inserted = 0;
+
+        // Cached statements have to be reset before they can be bound again.
+        // reset() hands back the error from the statement's last step, which
+        // was already reported with the write that caused it.
+        macro_rules! run {
+            ($stmt:expr $(, $param:expr)*) => {{
+                let stmt = &mut $stmt;
+                stmt.reset().ok();
+                let mut _i = 0;
+                $( _i += 1; stmt.bind((_i, $param))?; )*
+                stmt.next()?
+            }};
+        }
+
+        let mut apply = |write: &Write| -> Result<(), Box<dyn std::error::Error>> {
+            match write {
+                Write::InsertPost(post) => {
+                    let rules = serde_json::to_string(&post.reason.rules)?;
+                    run!(insert_post, post.uri.as_str(), post.cid.as_str(), post.did.as_str(), post.indexed_at, post.created_at,
+                        post.reply_parent.as_deref(), post.reply_root.as_deref(), rules.as_str(),
+                        post.reason.field.as_str(), post.reason.snippet.as_str());
+                    inserted += self.conn.change_count() as i32;
+                },
+                Write::DeletePost(uri) => {
+                    run!(delete_post, uri.as_str());
+                    if self.conn.change_count() > 0 {
+                        println!("[Ingestion] Post deleted by author, removed from feed: {}", uri);
+                        run!(delete_post_interactions, uri.as_str());
+                        run!(delete_post_replies, uri.as_str());
+                        run!(delete_post_quotes, uri.as_str());
+                    }
+
+                    // A deleted reply or quote comes back off its parent's count
+                    if run!(find_reply, uri.as_str()) == State::Row {
+                        let parent_uri = find_reply.read::<String, _>(0)?;
+                        run!(delete_reply, uri.as_str());
+                        run!(adjust_replies, -1i64, parent_uri.as_str());
+                        run!(refresh_score, parent_uri.as_str());
+                    }
+                    if run!(find_quote, uri.as_str()) == State::Row {
+                        let subject_uri = find_quote.read::<String, _>(0)?;
+                        run!(delete_quote, uri.as_str());
+                        run!(adjust_quotes, -1i64, subject_uri.as_str());
+                        run!(refresh_score, subject_uri.as_str());
+                    }
+                },
+                Write::DeletePostsByDid(did) => {
+                    run!(delete_posts_by_did, did.as_str());
+                    let deleted = self.conn.change_count();
+                    if deleted > 0 {
+                        println!("[Ingestion] Removed {} posts by {} from feed", deleted, did);
+                        self.conn.execute("DELETE FROM interactions WHERE subject_uri NOT IN (SELECT uri FROM posts)")?;
+                        self.conn.execute("DELETE FROM replies WHERE parent_uri NOT IN (SELECT uri FROM posts)")?;
+                        self.conn.execute("DELETE FROM quotes WHERE subject_uri NOT IN (SELECT uri FROM posts)")?;
+                    }
+                },
+                Write::SetHandle { did, handle: Some(handle) } => {
+                    run!(upsert_handle, did.as_str(), handle.as_str(), now, did.as_str());
+                },
+                Write::SetHandle { did, handle: None } => {
+                    run!(delete_handle, did.as_str());
+                },
+                Write::Interaction { actor_did, kind, rkey, subject_uri } => {
+                    run!(record_interaction, actor_did.as_str(), kind.as_str(), rkey.as_str(), subject_uri.as_str(), now, subject_uri.as_str());
+                    if self.conn.change_count() > 0 {
+                        match Column::for_interaction(kind) {
+                            Column::Likes => run!(adjust_likes, 1i64, subject_uri.as_str()),
+                            Column::Reposts => run!(adjust_reposts, 1i64, subject_uri.as_str()),
+                        };
+                        run!(refresh_score, subject_uri.as_str());
+                    }
+                },
+                Write::InteractionDeleted { actor_did, kind, rkey } => {
+                    if run!(find_interaction, actor_did.as_str(), kind.as_str(), rkey.as_str()) != State::Row {
+                        return Ok(());
+                    }
+                    let subject_uri = find_interaction.read::<String, _>(0)?;
+
+                    run!(delete_interaction, actor_did.as_str(), kind.as_str(), rkey.as_str());
+                    match Column::for_interaction(kind) {
+                        Column::Likes => run!(adjust_likes, -1i64, subject_uri.as_str()),
+                        Column::Reposts => run!(adjust_reposts, -1i64, subject_uri.as_str()),
+                    };
+                    run!(refresh_score, subject_uri.as_str());
+                },
+                Write::Reply { uri, parent_uri } => {
+                    run!(record_reply, uri.as_str(), parent_uri.as_str(), now, parent_uri.as_str());
+                    if self.conn.change_count() > 0 {
+                        run!(adjust_replies, 1i64, parent_uri.as_str());
+                        run!(refresh_score, parent_uri.as_str());
+                    }
+                },
+                Write::Quote { uri, subject_uri } => {
+                    run!(record_quote, uri.as_str(), subject_uri.as_str(), now, subject_uri.as_str());
+                    if self.conn.change_count() > 0 {
+                        run!(adjust_quotes, 1i64, subject_uri.as_str());
+                        run!(refresh_score, subject_uri.as_str());
+                    }
+                },
+                Write::Suppressed { rule, uri } => {
+                    run!(record_suppression, rule.as_str(), uri.as_str(), now);
+                },
+            }
+            Ok(())
+        };
+
+        // A write that fails for any reason is undone on its own and counted,
+        // everything else in the batch and the cursor still commit. Failing
+        // the batch instead would retry the same bad write forever.
+        let mut dropped = 0;
+        for write in writes {
+            run!(savepoint);
+            match apply(write) {
+                Ok(()) => {
+                    run!(release);
+                },
+                Err(e) => {
+                    run!(rollback_to);
+                    run!(release);
+                    dropped += 1;
+                    eprintln!("Dropped a write that failed: {}", e);
+                },
+            }
+        }
+
+        Ok((inserted, dropped))
+    }
+
     pub fn pop_posts(&mut self) {
         let q = "
             DELETE FROM posts WHERE indexed_at < (
@@ -89,40 +442,112 @@ impl Database {
             eprintln!("There was an deleting the table {}", e);
         }
 
+        let q = "DELETE FROM interactions WHERE subject_uri NOT IN (SELECT uri FROM posts)";
+        if let Err(e) = self.conn.execute(q) {
+            eprintln!("There was an error pruning interactions {}", e);
+        }
+
+        let q = "DELETE FROM replies WHERE parent_uri NOT IN (SELECT uri FROM posts)";
+        if let Err(e) = self.conn.execute(q) {
+            eprintln!("There was an error pruning replies {}", e);
+        }
+
+        let q = "DELETE FROM quotes WHERE subject_uri NOT IN (SELECT uri FROM posts)";
+        if let Err(e) = self.conn.execute(q) {
+            eprintln!("There was an error pruning quotes {}", e);
+        }
+
         self.counter = 0;
     }
 
-    pub fn delete_post(&self, uri: &str) {
-        let q = "DELETE FROM posts WHERE uri = ?";
+    pub fn has_post(&self, uri: &str) -> bool {
+        let q = "SELECT 1 FROM posts WHERE uri = ?";
         if let Ok(mut stmt) = self.conn.prepare(q) {
             stmt.bind((1, uri)).ok();
-            stmt.next().ok();
+            if let Ok(State::Row) = stmt.next() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Up to limit post URIs, oldest first so the newest end up most recently
+    /// used when loaded into an LRU
+    pub fn recent_post_uris(&self, limit: i64) -> Vec<String> {
+        let mut uris = Vec::new();
+
+        let q = "SELECT uri FROM (SELECT uri, indexed_at FROM posts ORDER BY indexed_at DESC LIMIT ?) ORDER BY indexed_at ASC";
+        if let Ok(mut stmt) = self.conn.prepare(q) {
+            stmt.bind((1, limit)).ok();
+            while let Ok(State::Row) = stmt.next() {
+                if let Ok(uri) = stmt.read::<String, _>(0) {
+                    uris.push(uri);
+                }
+            }
+        }
+
+        uris
+    }
+
+    /// Caches an author's handle, only for accounts that have posts in the feed.
+    /// None forgets the cached handle.
+    pub fn set_handle(&self, did: &str, handle: Option<&str>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        match handle {
+            Some(h) => {
+                let q = "INSERT OR REPLACE INTO handles (did, handle, updated_at)
+                    SELECT ?, ?, ? WHERE EXISTS (SELECT 1 FROM posts WHERE did = ?)";
+                if let Ok(mut stmt) = self.conn.prepare(q) {
+                    stmt.bind((1, did)).ok();
+                    stmt.bind((2, h)).ok();
+                    stmt.bind((3, now)).ok();
+                    stmt.bind((4, did)).ok();
+                    stmt.next().ok();
+                }
+            },
+            None => {
+                if let Ok(mut stmt) = self.conn.prepare("DELETE FROM handles WHERE did = ?") {
+                    stmt.bind((1, did)).ok();
+                    stmt.next().ok();
+                }
+            }
         }
     }
 
-    /// cursor is the indexed_at timestamp to paginate from
-    pub fn read_posts(&self, limit: i64, cursor: Option<i64>, seed: u32) -> (Vec<String>, Option<String>) {
+    /// cursor is the indexed_at timestamp to paginate from. collapse_threads
+    /// hides replies whose parent or root is also in the feed.
+    pub fn read_posts(&self, limit: i64, cursor: Option<i64>, seed: u32, collapse_threads: bool) -> (Vec<String>, Option<String>) {
         let mut posts: Vec<String> = Vec::new();
         let mut last_indexed_at: Option<i64> = None;
 
         let age_hours = "(strftime('%s', 'now') - CASE WHEN created_at > 0 THEN created_at ELSE indexed_at END) / 3600.0";
         let ranking_formula = format!(
-            "(score + {}) / (1.0 + ({} * {} * {})) + (({} + LENGTH(uri) * {}) % {})",
-            BASE_SCORE, age_hours, age_hours, DECAY_RATE, seed, SHUFFLE_MULT, SHUFFLE_MOD
+            "(score + {}) * ({}) / (1.0 + ({} * {} * {})) + (({} + LENGTH(uri) * {}) % {})",
+            BASE_SCORE, MATCH_WEIGHT, age_hours, age_hours, DECAY_RATE, seed, SHUFFLE_MULT, SHUFFLE_MOD
         );
 
+        let thread_filter = if collapse_threads {
+            "NOT EXISTS (SELECT 1 FROM posts p WHERE p.uri IN (posts.reply_parent, posts.reply_root))"
+        } else {
+            "1"
+        };
+
         let (q, needs_cursor_bind) = match cursor {
             Some(_) => (
                 format!(
-                    "SELECT uri, indexed_at FROM posts WHERE indexed_at < ? ORDER BY ({}) DESC LIMIT ?",
-                    ranking_formula
+                    "SELECT uri, indexed_at FROM posts WHERE indexed_at < ? AND {} ORDER BY ({}) DESC LIMIT ?",
+                    thread_filter, ranking_formula
                 ),
                 true
             ),
             None => (
                 format!(
-                    "SELECT uri, indexed_at FROM posts ORDER BY ({}) DESC LIMIT ?",
-                    ranking_formula
+                    "SELECT uri, indexed_at FROM posts WHERE {} ORDER BY ({}) DESC LIMIT ?",
+                    thread_filter, ranking_formula
                 ),
                 false
             ),
@@ -159,17 +584,98 @@ impl Database {
         (posts, next_cursor)
     }
 
-    pub fn increment_col(&self, uri: &str, column: Column) -> Result<(), Box<dyn std::error::Error>> {
-        let q = match column {
-            Column::Likes => "UPDATE posts SET likes = likes + 1, score = (likes + 1) + reposts * 2 WHERE uri = ?",
-            Column::Reposts => "UPDATE posts SET reposts = reposts + 1, score = likes + (reposts + 1) * 2 WHERE uri = ?",
+    /// Who liked/reposted a post, as (actor DID, kind) pairs
+    pub fn get_engagers(&self, uri: &str) -> Vec<(String, String)> {
+        let mut engagers = Vec::new();
+
+        let q = "SELECT actor_did, kind FROM interactions WHERE subject_uri = ? ORDER BY created_at ASC";
+        if let Ok(mut stmt) = self.conn.prepare(q) {
+            stmt.bind((1, uri)).ok();
+            while let Ok(State::Row) = stmt.next() {
+                if let (Ok(did), Ok(kind)) = (stmt.read::<String, _>(0), stmt.read::<String, _>(1)) {
+                    engagers.push((did, kind));
+                }
+            }
+        }
+
+        engagers
+    }
+
+    /// Why a post is in the feed, or None if it isn't. Posts stored before
+    /// reasons were recorded come back with an empty field.
+    pub fn get_match_reason(&self, uri: &str) -> Option<MatchReason> {
+        let q = "SELECT match_rules, match_field, match_snippet FROM posts WHERE uri = ?";
+        let mut stmt = self.conn.prepare(q).ok()?;
+        stmt.bind((1, uri)).ok()?;
+        if stmt.next().ok()? != State::Row {
+            return None;
+        }
+
+        let rules = stmt.read::<Option<String>, _>(0).ok().flatten()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        Some(MatchReason {
+            rules,
+            field: stmt.read::<Option<String>, _>(1).ok().flatten().unwrap_or_default(),
+            snippet: stmt.read::<Option<String>, _>(2).ok().flatten().unwrap_or_default(),
+        })
+    }
+
+    /// The at:// URI for a bsky.app post link, looking the author up in the
+    /// handles table if the link uses a handle. at:// URIs are passed through.
+    pub fn post_uri(&self, link: &str) -> Option<String> {
+        if link.starts_with("at://") {
+            return Some(link.to_string());
+        }
+
+        let path = link.split_once("bsky.app/profile/")?.1;
+        let (actor, rkey) = path.split_once("/post/")?;
+        let rkey = rkey.trim_end_matches('/');
+        let did = if actor.starts_with("did:") {
+            actor.to_string()
+        } else {
+            let mut stmt = self.conn.prepare("SELECT did FROM handles WHERE handle = ?").ok()?;
+            stmt.bind((1, actor)).ok()?;
+            if stmt.next().ok()? != State::Row {
+                return None;
+            }
+            stmt.read::<String, _>(0).ok()?
         };
-        
-        let mut stmt = self.conn.prepare(q)?;
-        stmt.bind((1, uri))?;
-        stmt.next()?;
 
-        Ok(())
+        Some(format!("at://{}/app.bsky.feed.post/{}", did, rkey))
+    }
+
+    /// How many posts each exclusion rule has suppressed, as rule name to
+    /// (count, latest suppressed post URI)
+    pub fn get_suppressions(&self) -> HashMap<String, (i64, String)> {
+        let mut suppressions = HashMap::new();
+
+        if let Ok(mut stmt) = self.conn.prepare("SELECT rule, count, last_uri FROM suppressions") {
+            while let Ok(State::Row) = stmt.next() {
+                if let (Ok(rule), Ok(count), Ok(last_uri)) =
+                    (stmt.read::<String, _>(0), stmt.read::<i64, _>(1), stmt.read::<String, _>(2)) {
+                    suppressions.insert(rule, (count, last_uri));
+                }
+            }
+        }
+
+        suppressions
+    }
+
+    /// For backfill, which writes directly rather than through apply_writes
+    pub fn record_suppression(&self, rule: &str, uri: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        if let Ok(mut stmt) = self.conn.prepare(RECORD_SUPPRESSION) {
+            stmt.bind((1, rule)).ok();
+            stmt.bind((2, uri)).ok();
+            stmt.bind((3, now)).ok();
+            if let Err(e) = stmt.next() {
+                eprintln!("Failed to record suppression: {}", e);
+            }
+        }
     }
 
     pub fn has_unenriched_posts(&self) -> bool {
@@ -214,6 +720,31 @@ impl Database {
         posts
     }
 
+    /// Counts an enrichment run that getPosts didn't return the post in, and
+    /// sends it to the back of the enrichment queue. Returns the misses in a row.
+    pub fn record_enrich_miss(&self, uri: &str) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let q = "UPDATE posts SET enrich_misses = enrich_misses + 1, last_enriched = ? WHERE uri = ?";
+        if let Ok(mut stmt) = self.conn.prepare(q) {
+            stmt.bind((1, now)).ok();
+            stmt.bind((2, uri)).ok();
+            stmt.next().ok();
+        }
+
+        let q = "SELECT enrich_misses FROM posts WHERE uri = ?";
+        if let Ok(mut stmt) = self.conn.prepare(q) {
+            stmt.bind((1, uri)).ok();
+            if let Ok(State::Row) = stmt.next() {
+                return stmt.read::<i64, _>(0).unwrap_or(0);
+            }
+        }
+        0
+    }
+
     pub fn backfill_created_at(&self, uri: &str, created_at: i64) {
         let q = "UPDATE posts SET created_at = ? WHERE uri = ? AND created_at = 0";
         if let Ok(mut stmt) = self.conn.prepare(q) {
@@ -238,10 +769,10 @@ impl Database {
 
         let score = likes + reposts*2 + quotes*3 + replies + bookmarks;
 
-        let q = "UPDATE posts SET likes=?, reposts=?, quotes=?, replies=?, bookmarks=?, score=?, last_enriched=? WHERE
-    uri=?";
+        let q = format!("UPDATE posts SET likes=?, reposts=?, quotes=?, replies=?, bookmarks=?, score=? + {} * {}, last_enriched=?, enrich_misses=0 WHERE
+    uri=?", LOCAL_ENGAGEMENT, LOCAL_ENGAGEMENT_WEIGHT);
 
-        if let Ok(mut stmt) = self.conn.prepare(q) {
+        if let Ok(mut stmt) = self.conn.prepare(&q) {
             stmt.bind((1, likes)).ok();
             stmt.bind((2, reposts)).ok();
             stmt.bind((3, quotes)).ok();
@@ -254,12 +785,10 @@ impl Database {
         }
     }
 
+    /// Inserts a post found by search along with the counts the API gave for it
     pub fn insert_post_if_not_exists(
         &mut self,
-        uri: &str,
-        cid: &str,
-        did: &str,
-        created_at: i64,
+        post: &TorontoPost,
         likes: i64,
         reposts: i64,
         quotes: i64,
@@ -277,50 +806,87 @@ impl Database {
         let score = likes + reposts * 2 + quotes * 3 + replies;
 
         let mut stmt = self.conn.prepare(
-            "INSERT OR IGNORE INTO posts (uri, cid, did, indexed_at, created_at, likes, reposts, quotes, replies, score, last_enriched)
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            "INSERT OR IGNORE INTO posts (uri, cid, did, indexed_at, created_at, likes, reposts, quotes, replies, score, last_enriched,
+                reply_parent, reply_root, match_rules, match_field, match_snippet)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
         )?;
-        stmt.bind((1, uri))?;
-        stmt.bind((2, cid))?;
-        stmt.bind((3, did))?;
-        stmt.bind((4, now))?;
-        stmt.bind((5, created_at))?;
+        stmt.bind((1, post.uri.as_str()))?;
+        stmt.bind((2, post.cid.as_str()))?;
+        stmt.bind((3, post.did.as_str()))?;
+        stmt.bind((4, post.indexed_at))?;
+        stmt.bind((5, post.created_at))?;
         stmt.bind((6, likes))?;
         stmt.bind((7, reposts))?;
         stmt.bind((8, quotes))?;
         stmt.bind((9, replies))?;
         stmt.bind((10, score))?;
         stmt.bind((11, now))?;
+        stmt.bind((12, post.reply_parent.as_deref()))?;
+        stmt.bind((13, post.reply_root.as_deref()))?;
+        stmt.bind((14, serde_json::to_string(&post.reason.rules)?.as_str()))?;
+        stmt.bind((15, post.reason.field.as_str()))?;
+        stmt.bind((16, post.reason.snippet.as_str()))?;
         stmt.next()?;
 
         self.counter += 1;
         Ok(())
     }
 
-    pub fn set_metadata(&self, metadata: &Metadata) {
-        let q = "INSERT OR REPLACE INTO metadata (key, value) VALUES ('cursor', ?)";
+    /// Starts a write transaction, so everything up to commit() lands
+    /// atomically. Fails (e.g. SQLITE_BUSY) rather than letting the writes
+    /// run one by one in autocommit.
+    fn begin(&self) -> Result<(), sqlite::Error> {
+        self.conn.execute("BEGIN IMMEDIATE")
+    }
+
+    /// Rolls back if the commit fails, so none of the transaction lands
+    fn commit(&self) -> Result<(), sqlite::Error> {
+        self.conn.execute("COMMIT").inspect_err(|_| {
+            self.conn.execute("ROLLBACK").ok();
+        })
+    }
+
+    /// Stores a cursor under `key`, and the time of the event it points at
+    /// (or now, if last_updated is 0) under `{key}_updated`
+    pub fn set_metadata(&self, key: &str, metadata: &Metadata) {
+        let q = "INSERT OR REPLACE INTO metadata (key, value) VALUES (?, ?)";
         if let Ok(mut stmt) = self.conn.prepare(q) {
-            stmt.bind((1, metadata.seq.to_string().as_str())).ok();
+            stmt.bind((1, key)).ok();
+            stmt.bind((2, metadata.seq.to_string().as_str())).ok();
             stmt.next().ok();
         }
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-        let q2 = "INSERT OR REPLACE INTO metadata (key, value) VALUES ('cursor_updated', ?)";
-        if let Ok(mut stmt) = self.conn.prepare(q2) {
-            stmt.bind((1, now.to_string().as_str())).ok();
+        let updated = if metadata.last_updated > 0 {
+            metadata.last_updated
+        } else {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64
+        };
+        if let Ok(mut stmt) = self.conn.prepare(q) {
+            stmt.bind((1, format!("{}_updated", key).as_str())).ok();
+            stmt.bind((2, updated.to_string().as_str())).ok();
+            stmt.next().ok();
+        }
+    }
+
+    pub fn delete_metadata(&self, key: &str) {
+        let q = "DELETE FROM metadata WHERE key = ? OR key = ?";
+        if let Ok(mut stmt) = self.conn.prepare(q) {
+            stmt.bind((1, key)).ok();
+            stmt.bind((2, format!("{}_updated", key).as_str())).ok();
             stmt.next().ok();
         }
     }
 
-    pub fn get_metadata(&self) -> Option<Metadata> {
+    pub fn get_metadata(&self, key: &str) -> Option<Metadata> {
         let mut seq: Option<i64> = None;
         let mut last_updated: i64 = 0;
 
-        let q = "SELECT value FROM metadata WHERE key = 'cursor'";
+        let q = "SELECT value FROM metadata WHERE key = ?";
         if let Ok(mut stmt) = self.conn.prepare(q) {
+            stmt.bind((1, key)).ok();
             if let Ok(State::Row) = stmt.next() {
                 if let Ok(seq_str) = stmt.read::<String, _>(0) {
                     seq = seq_str.parse::<i64>().ok();
@@ -328,8 +894,8 @@ impl Database {
             }
         }
 
-        let q2 = "SELECT value FROM metadata WHERE key = 'cursor_updated'";
-        if let Ok(mut stmt) = self.conn.prepare(q2) {
+        if let Ok(mut stmt) = self.conn.prepare(q) {
+            stmt.bind((1, format!("{}_updated", key).as_str())).ok();
             if let Ok(State::Row) = stmt.next() {
                 if let Ok(ts_str) = stmt.read::<String, _>(0) {
                     last_updated = ts_str.parse::<i64>().unwrap_or(0);
@@ -340,3 +906,228 @@ impl Database {
         seq.map(|s| Metadata { seq: s, last_updated })
     }
 }
+
+/// Hex CIDv1s always start with "01", which can't begin a base32 "b..." CID
+fn normalize_hex_cids(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows = Vec::new();
+    let mut stmt = conn.prepare("SELECT uri, cid FROM posts WHERE cid GLOB '01*'")?;
+    while let Ok(State::Row) = stmt.next() {
+        rows.push((stmt.read::<String, _>(0)?, stmt.read::<String, _>(1)?));
+    }
+
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    conn.execute("BEGIN IMMEDIATE")?;
+    let result = (|| -> Result<usize, Box<dyn std::error::Error>> {
+        let mut update = conn.prepare("UPDATE posts SET cid = ? WHERE uri = ?")?;
+        let mut normalized = 0;
+        for (uri, hex) in &rows {
+            let cid = match hex.parse::<Cid>() {
+                Ok(cid) => cid.to_string(),
+                Err(e) => {
+                    eprintln!("Skipping CID for {}: {}", uri, e);
+                    continue;
+                }
+            };
+
+            update.reset()?;
+            update.bind((1, cid.as_str()))?;
+            update.bind((2, uri.as_str()))?;
+            update.next()?;
+            normalized += 1;
+        }
+        Ok(normalized)
+    })();
+
+    let normalized = match result {
+        Ok(n) => n,
+        Err(e) => {
+            conn.execute("ROLLBACK").ok();
+            return Err(e);
+        }
+    };
+    conn.execute("COMMIT")?;
+
+    println!("Normalized {} hex CIDs to base32", normalized);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(uri: &str) -> TorontoPost {
+        TorontoPost {
+            uri: uri.to_string(),
+            cid: "bafyreib2rxk3rh6kzwq".to_string(),
+            did: uri.trim_start_matches("at://").split('/').next().unwrap().to_string(),
+            indexed_at: 1_700_000_000,
+            created_at: 1_700_000_000,
+            reply_parent: None,
+            reply_root: None,
+            reason: MatchReason::default(),
+        }
+    }
+
+    fn count(db: &Database, q: &str) -> i64 {
+        let mut stmt = db.conn.prepare(q).unwrap();
+        stmt.next().unwrap();
+        stmt.read::<i64, _>(0).unwrap()
+    }
+
+    #[test]
+    fn failed_writes_are_dropped_on_their_own() {
+        let mut db = Database::new(":memory:");
+        let (a, b) = ("at://did:plc:a/app.bsky.feed.post/1", "at://did:plc:b/app.bsky.feed.post/1");
+        db.apply_writes(&[Write::InsertPost(post(a))], None).unwrap();
+
+        // One insert that fails at its only step, and a delete that fails
+        // after it has already removed the post
+        db.conn.execute("
+            CREATE TRIGGER reject_b BEFORE INSERT ON posts WHEN NEW.uri LIKE 'at://did:plc:b/%'
+                BEGIN SELECT RAISE(ABORT, 'rejected'); END;
+            CREATE TRIGGER reject_cleanup BEFORE DELETE ON interactions
+                BEGIN SELECT RAISE(ABORT, 'rejected'); END;
+        ").unwrap();
+        let like = |rkey: &str| Write::Interaction {
+            actor_did: "did:plc:fan".to_string(),
+            kind: InteractionType::LIKE,
+            rkey: rkey.to_string(),
+            subject_uri: a.to_string(),
+        };
+        let writes = [Write::InsertPost(post(b)), like("1"), Write::DeletePost(a.to_string())];
+        db.apply_writes(&writes, Some(("cursor:test", &Metadata { seq: 42, last_updated: 0 }))).unwrap();
+
+        assert_eq!(db.dropped_writes(), 2);
+        assert!(!db.has_post(b));
+        // The half done delete was undone, the like around it still counts
+        assert!(db.has_post(a));
+        assert_eq!(count(&db, "SELECT likes FROM posts"), 1);
+        assert_eq!(db.get_metadata("cursor:test").map(|m| m.seq), Some(42));
+    }
+
+    #[test]
+    fn match_reasons_round_trip() {
+        let mut db = Database::new(":memory:");
+        let uri = "at://did:plc:a/app.bsky.feed.post/1";
+        let mut matched = post(uri);
+        matched.reason = MatchReason {
+            rules: vec!["toronto".to_string(), "king st".to_string()],
+            field: "image_alt".to_string(),
+            snippet: "...streetcar on King St in Toronto...".to_string(),
+        };
+        db.apply_writes(&[Write::InsertPost(matched)], None).unwrap();
+
+        let reason = db.get_match_reason(uri).unwrap();
+        assert_eq!(reason.rules, ["toronto", "king st"]);
+        assert_eq!(reason.field, "image_alt");
+        assert_eq!(reason.snippet, "...streetcar on King St in Toronto...");
+
+        // Rows from before reasons were recorded are still in the feed
+        db.conn.execute("UPDATE posts SET match_rules = NULL, match_field = NULL, match_snippet = NULL").unwrap();
+        let reason = db.get_match_reason(uri).unwrap();
+        assert!(reason.rules.is_empty() && reason.field.is_empty() && reason.snippet.is_empty());
+
+        assert!(db.get_match_reason("at://did:plc:a/app.bsky.feed.post/2").is_none());
+    }
+
+    #[test]
+    fn post_links_resolve_to_uris() {
+        let mut db = Database::new(":memory:");
+        let uri = "at://did:plc:a/app.bsky.feed.post/3m2qz";
+        db.apply_writes(&[Write::InsertPost(post(uri))], None).unwrap();
+        db.set_handle("did:plc:a", Some("alice.bsky.social"));
+
+        for (link, expected) in [
+            (uri, Some(uri)),
+            ("https://bsky.app/profile/did:plc:a/post/3m2qz", Some(uri)),
+            ("https://bsky.app/profile/alice.bsky.social/post/3m2qz/", Some(uri)),
+            ("https://bsky.app/profile/bob.bsky.social/post/3m2qz", None),
+            ("https://bsky.app/profile/alice.bsky.social", None),
+            ("alice.bsky.social/post/3m2qz", None),
+        ] {
+            assert_eq!(db.post_uri(link).as_deref(), expected, "{:?}", link);
+        }
+    }
+
+    fn reply(uri: &str, parent: &str, root: &str) -> TorontoPost {
+        TorontoPost { reply_parent: Some(parent.to_string()), reply_root: Some(root.to_string()), ..post(uri) }
+    }
+
+    #[test]
+    fn collapsing_threads_hides_replies_to_feed_posts() {
+        let mut db = Database::new(":memory:");
+        let root = "at://did:plc:op/app.bsky.feed.post/root";
+        let to_root = "at://did:plc:a/app.bsky.feed.post/1";
+        let nested = "at://did:plc:b/app.bsky.feed.post/1";
+        let outside = "at://did:plc:c/app.bsky.feed.post/1";
+        db.apply_writes(&[
+            Write::InsertPost(post(root)),
+            Write::InsertPost(reply(to_root, root, root)),
+            // Its parent isn't in the feed, but the root is
+            Write::InsertPost(reply(nested, "at://did:plc:x/app.bsky.feed.post/1", root)),
+            Write::InsertPost(reply(outside, "at://did:plc:x/app.bsky.feed.post/2", "at://did:plc:x/app.bsky.feed.post/1")),
+        ], None).unwrap();
+
+        let mut collapsed = db.read_posts(10, None, 0, true).0;
+        collapsed.sort();
+        assert_eq!(collapsed, [outside, root]);
+        assert_eq!(db.read_posts(10, None, 0, false).0.len(), 4);
+
+        // Once the root is gone its replies stand on their own
+        db.apply_writes(&[Write::DeletePost(root.to_string())], None).unwrap();
+        assert_eq!(db.read_posts(10, None, 0, true).0.len(), 3);
+    }
+
+    #[test]
+    fn replies_count_toward_feed_posts() {
+        let mut db = Database::new(":memory:");
+        let parent = "at://did:plc:op/app.bsky.feed.post/1";
+        let replies = ["at://did:plc:a/app.bsky.feed.post/1", "at://did:plc:b/app.bsky.feed.post/1"];
+        let reply_to = |uri: &str, parent_uri: &str| Write::Reply { uri: uri.to_string(), parent_uri: parent_uri.to_string() };
+        db.apply_writes(&[
+            Write::InsertPost(post(parent)),
+            reply_to(replies[0], parent),
+            reply_to(replies[1], parent),
+            // Redelivered
+            reply_to(replies[1], parent),
+            reply_to("at://did:plc:c/app.bsky.feed.post/1", "at://did:plc:x/app.bsky.feed.post/1"),
+        ], None).unwrap();
+        assert_eq!(count(&db, "SELECT replies FROM posts"), 2);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM replies"), 2);
+
+        db.apply_writes(&[Write::DeletePost(replies[0].to_string())], None).unwrap();
+        assert_eq!(count(&db, "SELECT replies FROM posts"), 1);
+
+        // Deleting the parent drops the replies it was tracking
+        db.apply_writes(&[Write::DeletePost(parent.to_string())], None).unwrap();
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM replies"), 0);
+    }
+
+    #[test]
+    fn quotes_count_toward_feed_posts() {
+        let mut db = Database::new(":memory:");
+        let quoted = "at://did:plc:op/app.bsky.feed.post/1";
+        let quotes = ["at://did:plc:a/app.bsky.feed.post/1", "at://did:plc:b/app.bsky.feed.post/1"];
+        let quote = |uri: &str, subject_uri: &str| Write::Quote { uri: uri.to_string(), subject_uri: subject_uri.to_string() };
+        db.apply_writes(&[
+            Write::InsertPost(post(quoted)),
+            quote(quotes[0], quoted),
+            quote(quotes[1], quoted),
+            // Redelivered
+            quote(quotes[1], quoted),
+            quote("at://did:plc:c/app.bsky.feed.post/1", "at://did:plc:x/app.bsky.feed.post/1"),
+        ], None).unwrap();
+        assert_eq!(count(&db, "SELECT quotes FROM posts"), 2);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM quotes"), 2);
+
+        db.apply_writes(&[Write::DeletePost(quotes[0].to_string())], None).unwrap();
+        assert_eq!(count(&db, "SELECT quotes FROM posts"), 1);
+
+        // The quoted post's author leaving takes its quotes with it
+        db.apply_writes(&[Write::DeletePostsByDid("did:plc:op".to_string())], None).unwrap();
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM quotes"), 0);
+    }
+}
@@ -0,0 +1,296 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::process::Command;
+
+// Multicodec prefixes on a Multikey publicKeyMultibase
+const MULTICODEC_SECP256K1_PUB: [u8; 2] = [0xe7, 0x01];
+const MULTICODEC_P256_PUB: [u8; 2] = [0x80, 0x24];
+
+// Lookups run on the decode workers, so they give up quickly and lean on the
+// verifier's failed lookup cache instead of retrying
+const CONNECT_TIMEOUT_SECS: &str = "2";
+const RESOLVE_TIMEOUT_SECS: &str = "4";
+// DID documents are a few KB at most
+const MAX_DOCUMENT_BYTES: &str = "65536";
+
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Looks up DID documents. The live resolver goes over the network; the
+/// fixture resolver reads them from a local file so verification can be
+/// exercised offline. Shared by all decode workers.
+pub trait DidResolver: Send + Sync {
+    fn resolve(&self, did: &str) -> Result<Value, String>;
+}
+
+/// Resolves did:plc through the PLC directory and did:web through the
+/// domain's /.well-known/did.json
+pub struct HttpResolver {
+    plc_url: String,
+}
+
+impl HttpResolver {
+    pub fn new() -> Self {
+        let plc_url = std::env::var("TOSKY_PLC_URL")
+            .unwrap_or_else(|_| "https://plc.directory".to_string());
+        HttpResolver { plc_url: plc_url.trim_end_matches('/').to_string() }
+    }
+}
+
+impl DidResolver for HttpResolver {
+    fn resolve(&self, did: &str) -> Result<Value, String> {
+        let mut curl = Command::new("curl");
+        curl.arg("-s")
+            .arg("-f")
+            .arg("--connect-timeout").arg(CONNECT_TIMEOUT_SECS)
+            .arg("--max-time").arg(RESOLVE_TIMEOUT_SECS)
+            .arg("--max-filesize").arg(MAX_DOCUMENT_BYTES)
+            // Redirects aren't followed, so a did:web host can't bounce the
+            // request somewhere its own address check wouldn't allow
+            .arg("--max-redirs").arg("0")
+            .arg("--proto").arg("=https");
+
+        let url = if did.starts_with("did:plc:") {
+            format!("{}/{}", self.plc_url, did)
+        } else if let Some(host) = did.strip_prefix("did:web:") {
+            // Pinned to the address that was checked, so curl can't resolve
+            // the name again to something else
+            let (host, port, addr) = did_web_target(host)?;
+            let addr = match addr {
+                IpAddr::V4(v4) => v4.to_string(),
+                IpAddr::V6(v6) => format!("[{}]", v6),
+            };
+            curl.arg("--resolve").arg(format!("{}:{}:{}", host, port, addr));
+            format!("https://{}:{}/.well-known/did.json", host, port)
+        } else {
+            return Err(format!("unsupported DID method: {}", did));
+        };
+
+        let output = curl.arg(&url)
+            .output()
+            .map_err(|e| format!("curl failed: {}", e))?;
+
+        if !output.status.success() {
+            return Err(format!("could not fetch {}", url));
+        }
+
+        serde_json::from_slice(&output.stdout).map_err(|e| format!("bad DID document from {}: {}", url, e))
+    }
+}
+
+/// The host, port and address to fetch a did:web document from. did:web
+/// names any host, so only public addresses are allowed: otherwise a repo
+/// could point the resolver at localhost or the internal network. Local
+/// did:web accounts can be verified with TOSKY_DID_FIXTURES instead.
+fn did_web_target(did_host: &str) -> Result<(String, u16, IpAddr), String> {
+    // Ports are percent-encoded in did:web, e.g. did:web:localhost%3A2583.
+    // A bare colon would be a path, which atproto doesn't use.
+    let decoded = did_host.replace("%3A", ":").replace("%3a", ":");
+    let (host, port) = match decoded.split_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| format!("bad did:web host {:?}", did_host))?),
+        None => (decoded.as_str(), 443),
+    };
+    if host.is_empty() || !host.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-') {
+        return Err(format!("bad did:web host {:?}", did_host));
+    }
+
+    let addrs: Vec<IpAddr> = (host, port).to_socket_addrs()
+        .map_err(|e| format!("could not resolve {}: {}", host, e))?
+        .map(|addr| addr.ip())
+        .collect();
+    // Every address has to be public, not just the one curl might pick
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr)) {
+        return Err(format!("did:web host {} resolves to non-public address {}", host, addr));
+    }
+    let addr = *addrs.first().ok_or_else(|| format!("{} has no addresses", host))?;
+
+    Ok((host.to_string(), port, addr))
+}
+
+/// False for loopback, private, link-local and other addresses that aren't
+/// reachable on the public internet
+fn is_public(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified()
+                || v4.is_broadcast() || v4.is_documentation() || v4.is_multicast()
+                // "This network", carrier-grade NAT and the reserved 240/4
+                || a == 0 || (a == 100 && (64..128).contains(&b)) || a >= 240)
+        },
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public(&IpAddr::V4(v4)),
+            None => {
+                let first = v6.segments()[0];
+                !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast()
+                    // Unique local fc00::/7 and link-local fe80::/10
+                    || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+            },
+        },
+    }
+}
+
+/// DID documents from a JSON file shaped like { "<did>": <document>, ... }
+pub struct FixtureResolver {
+    documents: HashMap<String, Value>,
+}
+
+impl FixtureResolver {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        let documents = serde_json::from_slice(&data).map_err(|e| format!("bad fixture file {}: {}", path, e))?;
+        Ok(FixtureResolver { documents })
+    }
+}
+
+impl DidResolver for FixtureResolver {
+    fn resolve(&self, did: &str) -> Result<Value, String> {
+        self.documents.get(did).cloned().ok_or_else(|| format!("{} is not in the fixture file", did))
+    }
+}
+
+/// TOSKY_DID_FIXTURES points at a fixture file to use instead of the network
+pub fn resolver_from_env() -> Box<dyn DidResolver> {
+    if let Ok(path) = std::env::var("TOSKY_DID_FIXTURES") {
+        match FixtureResolver::open(&path) {
+            Ok(resolver) => {
+                println!("[Ingestion] Resolving DIDs from fixture file {}", path);
+                return Box::new(resolver);
+            },
+            Err(e) => eprintln!("[Ingestion] {}, falling back to network resolution", e),
+        }
+    }
+
+    Box::new(HttpResolver::new())
+}
+
+/// A repo's atproto signing key. Repos sign with either secp256k1 or NIST P-256.
+pub enum SigningKey {
+    K256(k256::ecdsa::VerifyingKey),
+    P256(p256::ecdsa::VerifyingKey),
+}
+
+impl SigningKey {
+    /// Pulls the #atproto verification method out of a DID document
+    pub fn from_did_document(document: &Value, did: &str) -> Result<Self, String> {
+        if document["id"].as_str() != Some(did) {
+            return Err(format!("DID document is for {:?}, not {}", document["id"].as_str(), did));
+        }
+
+        let method = document["verificationMethod"].as_array()
+            .and_then(|methods| methods.iter().find(|m| {
+                m["id"].as_str().is_some_and(|id| id == "#atproto" || id == format!("{}#atproto", did))
+            }))
+            .ok_or_else(|| format!("{} has no #atproto verification method", did))?;
+
+        let multibase = method["publicKeyMultibase"].as_str()
+            .ok_or_else(|| format!("{}#atproto has no publicKeyMultibase", did))?;
+
+        SigningKey::from_multibase(multibase)
+    }
+
+    /// Decodes a Multikey, "z" + base58btc(multicodec prefix + compressed key)
+    pub fn from_multibase(multibase: &str) -> Result<Self, String> {
+        let encoded = multibase.strip_prefix('z')
+            .ok_or_else(|| format!("key {} is not base58btc multibase", multibase))?;
+        let bytes = decode_base58(encoded)?;
+
+        if let Some(key) = bytes.strip_prefix(&MULTICODEC_SECP256K1_PUB) {
+            k256::ecdsa::VerifyingKey::from_sec1_bytes(key)
+                .map(SigningKey::K256)
+                .map_err(|e| format!("bad secp256k1 key: {}", e))
+        } else if let Some(key) = bytes.strip_prefix(&MULTICODEC_P256_PUB) {
+            p256::ecdsa::VerifyingKey::from_sec1_bytes(key)
+                .map(SigningKey::P256)
+                .map_err(|e| format!("bad P-256 key: {}", e))
+        } else {
+            Err(format!("unsupported key type in {}", multibase))
+        }
+    }
+
+    /// Checks a 64 byte compact (r, s) signature over sha-256(message).
+    /// atproto only accepts low-S signatures, so high-S ones are rejected.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        use k256::ecdsa::signature::Verifier;
+
+        match self {
+            SigningKey::K256(key) => k256::ecdsa::Signature::from_slice(signature)
+                .is_ok_and(|sig| sig.normalize_s().is_none() && key.verify(message, &sig).is_ok()),
+            SigningKey::P256(key) => p256::ecdsa::Signature::from_slice(signature)
+                .is_ok_and(|sig| sig.normalize_s().is_none() && key.verify(message, &sig).is_ok()),
+        }
+    }
+}
+
+fn decode_base58(s: &str) -> Result<Vec<u8>, String> {
+    // Big-endian base 256 digits, built up one base58 digit at a time
+    let mut bytes: Vec<u8> = Vec::new();
+
+    for c in s.bytes() {
+        let mut carry = BASE58_ALPHABET.iter()
+            .position(|&a| a == c)
+            .ok_or_else(|| format!("bad base58 character {:?}", c as char))? as u32;
+
+        for byte in bytes.iter_mut().rev() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    // Each leading '1' is a leading zero byte
+    let zeros = s.bytes().take_while(|&c| c == b'1').count();
+    let mut out = vec![0u8; zeros];
+    out.extend(bytes);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_addresses() {
+        for (addr, public) in [
+            ("1.1.1.1", true),
+            ("2606:4700:4700::1111", true),
+            ("127.0.0.1", false),
+            ("10.1.2.3", false),
+            ("172.16.0.1", false),
+            ("192.168.1.1", false),
+            ("169.254.169.254", false),
+            ("100.64.0.1", false),
+            ("0.0.0.0", false),
+            ("::1", false),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("::ffff:127.0.0.1", false),
+        ] {
+            assert_eq!(is_public(&addr.parse().unwrap()), public, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn did_web_hosts() {
+        // IP literals and localhost, so none of these need DNS
+        let target = did_web_target("1.1.1.1%3A8443").unwrap();
+        assert_eq!(target, ("1.1.1.1".to_string(), 8443, "1.1.1.1".parse().unwrap()));
+
+        for (host, error) in [
+            ("localhost%3A2583", "did:web host localhost resolves to non-public address"),
+            ("127.0.0.1", "did:web host 127.0.0.1 resolves to non-public address"),
+            ("169.254.169.254", "did:web host 169.254.169.254 resolves to non-public address"),
+            ("example.com:user:alice", "bad did:web host"),
+            ("example.com%3Ahttps", "bad did:web host"),
+            ("user@example.com", "bad did:web host"),
+            ("", "bad did:web host"),
+        ] {
+            let result = did_web_target(host);
+            assert!(result.as_ref().is_err_and(|e| e.starts_with(error)), "{:?}: {:?}", host, result);
+        }
+    }
+}
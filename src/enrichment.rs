@@ -1,7 +1,13 @@
-use crate::db::Database;
+use crate::db::{Database, Write};
 use std::process::Command;
 use serde_json::Value;
-use crate::filter::NSFW_LABELS;
+use crate::rules;
+
+// getPosts leaves out posts that are deleted, but also ones it can't load
+// right now, so a post is only deleted once it's been missing this many runs
+// in a row. Each miss sends the post to the back of the queue, so the runs
+// are spread out.
+const MISSES_BEFORE_DELETE: i64 = 3;
 
 pub struct EnrichThread {
     db: Database,
@@ -30,7 +36,7 @@ impl EnrichThread {
         }
     }
 
-    pub fn enrich_what_we_missed(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn enrich_what_we_missed(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let uris = self.db.get_posts_to_enrich(25);
         println!("[Enrichment] Fetching engagement for {} posts", uris.len());
 
@@ -53,56 +59,154 @@ impl EnrichThread {
         let body = String::from_utf8(output.stdout).unwrap();
 
         let json: Value = serde_json::from_str(&body)?;
+        if json["posts"].is_array() {
+            self.apply_response(&uris, &json)?;
+        } else {
+            println!("[Enrichment] No posts in response. Body: {}", &body[..200.min(body.len())]);
+        }
+
+        Ok(())
+    }
+
+    /// Stores the engagement from a getPosts response for uris, and deletes
+    /// NSFW posts and posts that have been missing too many times
+    fn apply_response(&mut self, uris: &[String], json: &Value) -> Result<(), Box<dyn std::error::Error>> {
+        let posts = json["posts"].as_array().map(Vec::as_slice).unwrap_or_default();
         let mut found_uris: Vec<&str> = Vec::new();
+        let mut deletes = Vec::new();
+        let rules = rules::current();
 
-        if let Some(posts) = json["posts"].as_array() {
-            println!("[Enrichment] Got {} posts from API", posts.len());
+        println!("[Enrichment] Got {} posts from API", posts.len());
 
-            for post in posts {
-                let uri = post["uri"].as_str().unwrap_or("");
+        for post in posts {
+            let uri = post["uri"].as_str().unwrap_or("");
 
-                if let Some(labels) = post["labels"].as_array() {
-                    let is_nsfw = labels.iter().any(|l| {
-                        let val = l["val"].as_str().unwrap_or("");
-                        NSFW_LABELS.contains(&val)
-                    });
+            if let Some(labels) = post["labels"].as_array() {
+                let is_nsfw = labels.iter().any(|l| {
+                    let val = l["val"].as_str().unwrap_or("");
+                    rules.nsfw_labels.iter().any(|label| label == val)
+                });
 
-                    if is_nsfw {
-                        println!("[Enrichment] NSFW post, deleting: {}", uri);
-                        self.db.delete_post(uri);
-                        continue;
-                    }
-                }
-                
-                let likes = post["likeCount"].as_i64().unwrap_or(0);
-                let reposts = post["repostCount"].as_i64().unwrap_or(0);
-                let quotes = post["quoteCount"].as_i64().unwrap_or(0);
-                let replies = post["replyCount"].as_i64().unwrap_or(0);
-                let bookmarks = post["bookmarkCount"].as_i64().unwrap_or(0);
-
-                println!("[Enrichment] {} - L:{} R:{} Q:{}", uri, likes, reposts, quotes);
-                found_uris.push(uri);
-
-                self.db.update_engagement(uri, likes, reposts, quotes, replies, bookmarks);
-
-                if let Some(created_str) = post["record"]["createdAt"].as_str() {
-                    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(created_str) {
-                        self.db.backfill_created_at(uri, dt.timestamp());
-                    }
+                if is_nsfw {
+                    println!("[Enrichment] NSFW post, deleting: {}", uri);
+                    found_uris.push(uri);
+                    deletes.push(Write::DeletePost(uri.to_string()));
+                    continue;
                 }
             }
+            
+            let likes = post["likeCount"].as_i64().unwrap_or(0);
+            let reposts = post["repostCount"].as_i64().unwrap_or(0);
+            let quotes = post["quoteCount"].as_i64().unwrap_or(0);
+            let replies = post["replyCount"].as_i64().unwrap_or(0);
+            let bookmarks = post["bookmarkCount"].as_i64().unwrap_or(0);
 
-            for uri in &uris {
-                if !found_uris.contains(&uri.as_str()) {
-                    println!("[Enrichment] Post not found in API response, skipping: {}", uri);
+            println!("[Enrichment] {} - L:{} R:{} Q:{}", uri, likes, reposts, quotes);
+            found_uris.push(uri);
+
+            self.db.update_engagement(uri, likes, reposts, quotes, replies, bookmarks);
+
+            if let (Some(did), Some(handle)) = (post["author"]["did"].as_str(), post["author"]["handle"].as_str()) {
+                self.db.set_handle(did, Some(handle));
+            }
+
+            if let Some(created_str) = post["record"]["createdAt"].as_str() {
+                if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(created_str) {
+                    self.db.backfill_created_at(uri, dt.timestamp());
                 }
             }
+        }
 
+        // An answer with none of the posts in it is more likely the API
+        // having trouble than every one of them being deleted
+        if posts.is_empty() {
+            println!("[Enrichment] None of the {} posts came back, not counting them as missing", uris.len());
         } else {
-            println!("[Enrichment] No posts in response. Body: {}", &body[..200.min(body.len())]);
+            for uri in uris {
+                if found_uris.contains(&uri.as_str()) {
+                    continue;
+                }
+
+                let misses = self.db.record_enrich_miss(uri);
+                if misses >= MISSES_BEFORE_DELETE {
+                    println!("[Enrichment] Post missing from {} API responses in a row, deleting: {}", misses, uri);
+                    deletes.push(Write::DeletePost(uri.clone()));
+                } else {
+                    println!("[Enrichment] Post not found in API response ({} of {}): {}", misses, MISSES_BEFORE_DELETE, uri);
+                }
+            }
         }
 
-        Ok(())
+        self.db.apply_writes(&deletes, None)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{MatchReason, TorontoPost};
+    use serde_json::json;
+
+    fn enrich_thread(uris: &[&str]) -> EnrichThread {
+        let mut db = Database::new(":memory:");
+        let posts: Vec<Write> = uris.iter().map(|uri| Write::InsertPost(TorontoPost {
+            uri: uri.to_string(),
+            cid: "bafyreib2rxk3rh6kzwq".to_string(),
+            did: uri.trim_start_matches("at://").split('/').next().unwrap().to_string(),
+            indexed_at: 1_700_000_000,
+            created_at: 1_700_000_000,
+            reply_parent: None,
+            reply_root: None,
+            reason: MatchReason::default(),
+        })).collect();
+        db.apply_writes(&posts, None).unwrap();
+        EnrichThread { db, logged_normal_mode: false }
+    }
+
+    #[test]
+    fn posts_are_deleted_after_repeated_misses() {
+        let (kept, missing) = ("at://did:plc:a/app.bsky.feed.post/1", "at://did:plc:b/app.bsky.feed.post/1");
+        let mut enrich = enrich_thread(&[kept, missing]);
+        let uris = [kept.to_string(), missing.to_string()];
+        let response = json!({ "posts": [{ "uri": kept, "likeCount": 3 }] });
+
+        for _ in 1..MISSES_BEFORE_DELETE {
+            enrich.apply_response(&uris, &response).unwrap();
+            assert!(enrich.db.has_post(missing));
+        }
+
+        // Showing up again starts the count over
+        enrich.apply_response(&uris, &json!({ "posts": [{ "uri": kept }, { "uri": missing }] })).unwrap();
+        for _ in 1..MISSES_BEFORE_DELETE {
+            enrich.apply_response(&uris, &response).unwrap();
+        }
+        assert!(enrich.db.has_post(missing));
+
+        enrich.apply_response(&uris, &response).unwrap();
+        assert!(!enrich.db.has_post(missing));
+        assert!(enrich.db.has_post(kept));
+    }
+
+    #[test]
+    fn empty_responses_are_not_misses() {
+        let uri = "at://did:plc:a/app.bsky.feed.post/1";
+        let mut enrich = enrich_thread(&[uri]);
+        for _ in 0..MISSES_BEFORE_DELETE {
+            enrich.apply_response(&[uri.to_string()], &json!({ "posts": [] })).unwrap();
+        }
+        assert!(enrich.db.has_post(uri));
+    }
+
+    #[test]
+    fn nsfw_posts_are_deleted() {
+        let (nsfw, fine) = ("at://did:plc:a/app.bsky.feed.post/1", "at://did:plc:b/app.bsky.feed.post/1");
+        let mut enrich = enrich_thread(&[nsfw, fine]);
+        let response = json!({ "posts": [
+            { "uri": nsfw, "labels": [{ "val": "porn" }] },
+            { "uri": fine, "labels": [{ "val": "something-else" }] },
+        ] });
+        enrich.apply_response(&[nsfw.to_string(), fine.to_string()], &response).unwrap();
+        assert!(!enrich.db.has_post(nsfw));
+        assert!(enrich.db.has_post(fine));
+    }
+}
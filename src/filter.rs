@@ -1,119 +1,212 @@
-use crate::models::{Feature, Frame, Operation, Post, TorontoPost, Embed, StrongRef, InteractionType};
-use crate::db::{Column, Database};
+use crate::models::{Feature, Post, TorontoPost, MatchReason, Embed, StrongRef, InteractionType, Cid};
+use crate::db::{Database, Metadata, Write};
+use crate::matcher::{tokenize, Candidates, Matcher};
+use crate::rules;
 use lru::LruCache;
+use std::collections::HashSet;
 use std::num::NonZeroUsize;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub const NSFW_LABELS: &[&str] = &["porn", "nudity", "sexual", "graphic-media", "nsfw"];
 const CAUGHT_UP_THRESHOLD_SECS: i64 = 3600; // 1 hour
+const BATCH_MAX_WRITES: usize = 1000;
+const BATCH_MAX_MS: u64 = 500;
+const FEED_CACHE_SIZE: usize = 100_000;
 
-pub const LAX_KEYWORDS: &[&str] = &[
-    "toronto",
-    "torono",
-];
-
-pub const STRICT_KEYWORDS: &[&str] = &[
-    "ttc",
-    "cn tower",
-    "6ix",
-    "Danforth Music Hall",
-    "bluejays",
-    "Scotiabank arena",
-    "air canada centre",
-    "Rogers centre",
-    "Rogers Stadium",
-    "Trillium Park",
-    "Olivia Chow",
-    "Kensington Market",
-    "Yonge",
-    "Roncesvalles",
-    "YYZ",
-    "metrolinx",
-];
+/// What to do with replies in threads that are already in the feed
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+    /// Replies to feed posts join the feed even without a keyword
+    Include,
+    /// Replies only join on their own merits, but count toward the parent
+    Count,
+    /// Replies to feed posts never join the feed, they only count toward the parent
+    Exclude,
+}
+
+impl ReplyMode {
+    /// TOSKY_REPLY_MODE=include|count|exclude, count by default
+    pub fn from_env() -> Self {
+        match std::env::var("TOSKY_REPLY_MODE").as_deref() {
+            Ok("include") => {
+                println!("[Ingestion] Including replies to posts in the feed");
+                ReplyMode::Include
+            },
+            Ok("exclude") => {
+                println!("[Ingestion] Excluding replies to posts in the feed");
+                ReplyMode::Exclude
+            },
+            _ => ReplyMode::Count,
+        }
+    }
+}
 
 pub struct Filter {
     pub db: Database,
     toronto_uris: LruCache<String, ()>,
+    reply_mode: ReplyMode,
     caught_up: bool,
+    pending: Vec<Write>,
+    /// Post URIs and DIDs whose posts are deleted in pending, so in_feed
+    /// doesn't find them in the DB before the batch is flushed
+    unflushed_deletes: HashSet<String>,
+    last_flush: Instant,
 }
 
 impl Filter {
     pub fn new(db: Database) -> Self {
+        // Warmed from the DB so quotes and replies of posts from before a
+        // restart, or from backfill, are recognised without a lookup
+        let mut toronto_uris = LruCache::new(NonZeroUsize::new(FEED_CACHE_SIZE).unwrap());
+        for uri in db.recent_post_uris(FEED_CACHE_SIZE as i64) {
+            toronto_uris.put(uri, ());
+        }
+        println!("[Ingestion] Warmed feed cache with {} posts", toronto_uris.len());
+
         Filter {
             db,
-            toronto_uris: LruCache::new(NonZeroUsize::new(100_000).unwrap()),
+            toronto_uris,
+            reply_mode: ReplyMode::from_env(),
             caught_up: false,
+            pending: Vec::new(),
+            unflushed_deletes: HashSet::new(),
+            last_flush: Instant::now(),
         }
     }
 
-    fn is_6ix_post(&self, post: &Post) -> bool {
-        if let Some(Embed::Record { record }) = &post.embed {
-            if self.toronto_uris.contains(&record.uri) {
-                return true;
-            }
+    /// Whether a post is in the feed. The cache holds everything inserted
+    /// since startup plus the newest posts from before it, and anything else
+    /// falls back to the DB.
+    fn in_feed(&self, uri: &str) -> bool {
+        if self.toronto_uris.contains(uri) {
+            return true;
         }
 
-        let mut text = post.text.to_lowercase();
+        let did = uri.strip_prefix("at://").and_then(|rest| rest.split('/').next());
+        if self.unflushed_deletes.contains(uri) || did.is_some_and(|did| self.unflushed_deletes.contains(did)) {
+            return false;
+        }
 
-        // We're just going to concat all the text in the post to text 
-        // (facets included) and then just do one check
+        self.db.has_post(uri)
+    }
 
-        for facet in post.facets.iter().flatten() {
-            for feat in &facet.features {
-                match feat {
-                    Feature::Tag { tag } => {
-                        text = text + " TAG: " + &tag.to_lowercase()
-                    },
-                    Feature::Link { uri } => {
-                        text = text + " LINK: " + &uri.to_lowercase()
-                    },
-                    _ => {}
-                }
-            }
-        }
+    /// Quotes of posts already in the feed count even without a keyword.
+    /// Returns the quoted post.
+    fn quotes_6ix_post<'a>(&self, post: &'a Post) -> Option<&'a str> {
+        post.quoted_uri().filter(|uri| self.in_feed(uri))
+    }
+
+    /// Replies whose parent or root is a post already in the feed. Returns
+    /// whichever of the two is in the feed, the parent if both are.
+    fn replies_in_6ix_thread<'a>(&self, post: &'a Post) -> Option<&'a str> {
+        let reply = post.reply.as_ref()?;
+        [&reply.parent.uri, &reply.root.uri].into_iter()
+            .find(|uri| self.in_feed(uri))
+            .map(String::as_str)
+    }
 
-        if let Some(Embed::Video { alt: Some(alt_text)}) = &post.embed {
-            text = text + " ALT: " + &alt_text.to_lowercase()
+    /// Cheapest checks first, the quote and thread checks can hit the DB
+    fn inclusion(&self, post: &Post, keyword_match: Option<KeywordMatch>) -> Option<Inclusion> {
+        let keyword = keyword_match.map(Inclusion::Keyword);
+        let quote = || self.quotes_6ix_post(post).map(|uri| Inclusion::Quote(uri.to_string()));
+        let thread = || self.replies_in_6ix_thread(post).map(|uri| Inclusion::Thread(uri.to_string()));
+
+        match self.reply_mode {
+            ReplyMode::Include => keyword.or_else(quote).or_else(thread),
+            ReplyMode::Count => keyword.or_else(quote),
+            ReplyMode::Exclude => keyword.or_else(quote).filter(|_| thread().is_none()),
         }
+    }
 
-        if LAX_KEYWORDS.iter().any(|k| text.contains(k))
-            || STRICT_KEYWORDS.iter().any(|k| self.contains_word_strict(&text, k)) {
-            return true;
+    /// Applies an event decoded on a worker thread
+    pub fn apply(&mut self, event: Event) {
+        match event {
+            Event::Post { repo, path, cid, post, keyword_match } => self.on_post(&repo, &path, &cid, &post, keyword_match),
+            Event::Interaction { actor_did, rkey, subject, kind } => self.on_interaction(&actor_did, &rkey, &subject, kind),
+            Event::InteractionDeleted { actor_did, rkey, kind } => self.on_interaction_deleted(&actor_did, &rkey, kind),
+            Event::PostDeleted(uri) => self.on_post_deleted(&uri),
+            Event::Identity { did, handle } => self.on_identity(&did, handle.as_deref()),
+            Event::AccountStatus { did, active, status } => self.on_account_status(&did, active, status.as_deref()),
         }
+    }
 
-        false
+    pub fn on_interaction(&mut self, actor_did: &str, rkey: &str, subject: &StrongRef, interaction_type: InteractionType) {
+        self.pending.push(Write::Interaction {
+            actor_did: actor_did.to_string(),
+            kind: interaction_type,
+            rkey: rkey.to_string(),
+            subject_uri: subject.uri.clone(),
+        });
     }
 
-    fn is_nsfw(&self, post: &Post) -> bool {
-        if let Some(labels) = &post.labels {
-            return labels.values.iter().any(|l| NSFW_LABELS.contains(&l.val.as_str()));
-        }
+    pub fn on_interaction_deleted(&mut self, actor_did: &str, rkey: &str, interaction_type: InteractionType) {
+        self.pending.push(Write::InteractionDeleted {
+            actor_did: actor_did.to_string(),
+            kind: interaction_type,
+            rkey: rkey.to_string(),
+        });
+    }
+
+    pub fn on_post_deleted(&mut self, uri: &str) {
+        self.pending.push(Write::DeletePost(uri.to_string()));
+        self.unflushed_deletes.insert(uri.to_string());
+        self.toronto_uris.pop(uri);
+    }
 
-        false
+    pub fn on_identity(&mut self, did: &str, handle: Option<&str>) {
+        self.pending.push(Write::SetHandle { did: did.to_string(), handle: handle.map(String::from) });
     }
 
-    fn contains_word_strict(&self, text: &str, word: &str) -> bool {
-        text.split_whitespace()
-          .any(|w| w.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase() == word)
+    pub fn on_account_status(&mut self, did: &str, active: bool, status: Option<&str>) {
+        if active || !matches!(status, Some("takendown" | "suspended" | "deleted")) {
+            return;
+        }
+
+        println!("[Ingestion] Account {} is {}, removing their posts", did, status.unwrap_or_default());
+        self.pending.push(Write::DeletePostsByDid(did.to_string()));
+        self.unflushed_deletes.insert(did.to_string());
+
+        let prefix = format!("at://{}/", did);
+        let stale: Vec<String> = self.toronto_uris.iter()
+            .filter(|(uri, _)| uri.starts_with(&prefix))
+            .map(|(uri, _)| uri.clone())
+            .collect();
+        for uri in stale {
+            self.toronto_uris.pop(&uri);
+        }
     }
 
-    fn bytes_to_hex(&self, bytes: &[u8]) -> String {
-        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    /// True once enough writes have queued up, or the oldest has waited long enough
+    pub fn needs_flush(&self) -> bool {
+        self.pending.len() >= BATCH_MAX_WRITES
+            || self.last_flush.elapsed() >= Duration::from_millis(BATCH_MAX_MS)
     }
 
-    pub fn on_interaction(&mut self, subject: &StrongRef, interaction_type: InteractionType) {
-        if interaction_type == InteractionType::LIKE {
-            if let Err(res) = self.db.increment_col(&subject.uri, Column::Likes) {
-                eprintln!("Ran into an error {}", res);
-            }
-        } else if interaction_type == InteractionType::REPOST {
-            if let Err(res) = self.db.increment_col(&subject.uri, Column::Reposts) {
-                eprintln!("Ran into an error {}", res);
-            }
+    /// Commits queued writes, and the cursor they lead up to, in one
+    /// transaction. Returns false if that failed, in which case the writes
+    /// stay queued for the next flush and the cursor wasn't stored.
+    pub fn flush(&mut self, cursor: Option<(&str, &Metadata)>) -> bool {
+        self.last_flush = Instant::now();
+        if self.pending.is_empty() && cursor.is_none() {
+            return true;
         }
+
+        if let Err(e) = self.db.apply_writes(&self.pending, cursor) {
+            eprintln!("Failed to apply {} writes, will retry: {}", self.pending.len(), e);
+            return false;
+        }
+
+        self.pending.clear();
+        self.unflushed_deletes.clear();
+        true
+    }
+
+    pub fn callback(&mut self, repo: &str, path: &str, cid: &Cid, post: &Post) {
+        self.on_post(repo, path, cid, post, matches_keywords(post));
     }
 
-    pub fn callback(&mut self, frame: &Frame, op: &Operation, post: &Post) {
+    /// keyword_match is matches_keywords(post), which decode workers work out
+    /// ahead of time so only the cheap checks happen here
+    fn on_post(&mut self, repo: &str, path: &str, cid: &Cid, post: &Post, keyword_match: Option<KeywordMatch>) {
         if !self.caught_up {
             if let Ok(created) = chrono::DateTime::parse_from_rfc3339(&post.created_at) {
                 let now = SystemTime::now()
@@ -128,28 +221,395 @@ impl Filter {
             }
         }
 
-        if self.is_nsfw(post) || !self.is_6ix_post(post) {
+        let uri = format!("at://{}/{}", repo, path);
+
+        // Counted whatever the post itself matches, the DB ignores replies
+        // and quotes of posts outside the feed
+        if let Some(reply) = &post.reply {
+            self.pending.push(Write::Reply { uri: uri.clone(), parent_uri: reply.parent.uri.clone() });
+        }
+        if let Some(quoted_uri) = post.quoted_uri() {
+            self.pending.push(Write::Quote { uri: uri.clone(), subject_uri: quoted_uri.to_string() });
+        }
+
+        if is_nsfw(post) {
             return;
         }
 
-        println!("---POST [{}]--- \n {}\n ------- \n", post.created_at, post.text);
+        let inclusion = match self.inclusion(post, keyword_match) {
+            Some(inclusion) => inclusion,
+            None => return,
+        };
+
+        if let Some(rule) = excluded_by(repo, post) {
+            println!("[Ingestion] Suppressed {} ({}) by exclusion \"{}\"", uri, inclusion, rule);
+            self.pending.push(Write::Suppressed { rule, uri });
+            return;
+        }
+
+        println!("---POST [{}] ({})--- \n {}\n ------- \n", post.created_at, inclusion, post.text);
 
         let created_at = chrono::DateTime::parse_from_rfc3339(&post.created_at)
             .map(|dt| dt.timestamp())
             .unwrap_or_else(|_| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64);
 
         let toronto_post = TorontoPost {
-            uri: format!("at://{}/{}", frame.repo, op.path),
-            cid: self.bytes_to_hex(&op.cid.as_ref().unwrap()[1..]),
-            did: frame.repo.clone(),
+            uri,
+            cid: cid.to_string(),
+            did: repo.to_string(),
             indexed_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
             created_at,
+            reply_parent: post.reply.as_ref().map(|reply| reply.parent.uri.clone()),
+            reply_root: post.reply.as_ref().map(|reply| reply.root.uri.clone()),
+            reason: inclusion.into(),
         };
 
-        if let Err(e) = self.db.insert_post(&toronto_post) {
-            eprintln!("Failed to insert post: {}", e);
+        self.toronto_uris.put(toronto_post.uri.clone(), ());
+        self.pending.push(Write::InsertPost(toronto_post));
+    }
+}
+
+/// A decoded firehose event for the filter. Built on the decode workers, where
+/// keyword matching has already run, and applied in order on the writer.
+pub enum Event {
+    Post { repo: String, path: String, cid: Cid, post: Box<Post>, keyword_match: Option<KeywordMatch> },
+    Interaction { actor_did: String, rkey: String, subject: StrongRef, kind: InteractionType },
+    InteractionDeleted { actor_did: String, rkey: String, kind: InteractionType },
+    PostDeleted(String),
+    Identity { did: String, handle: Option<String> },
+    AccountStatus { did: String, active: bool, status: Option<String> },
+}
+
+/// Which part of a post a keyword was found in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchSource {
+    Text,
+    /// A #hashtag facet in the text
+    Hashtag,
+    /// A link facet in the text
+    Link,
+    /// The record's own tags, which aren't shown in the text
+    Tag,
+    ImageAlt,
+    VideoAlt,
+    CardUrl,
+    CardTitle,
+    CardDescription,
+}
+
+impl MatchSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchSource::Text => "text",
+            MatchSource::Hashtag => "hashtag",
+            MatchSource::Link => "link",
+            MatchSource::Tag => "tag",
+            MatchSource::ImageAlt => "image alt text",
+            MatchSource::VideoAlt => "video alt text",
+            MatchSource::CardUrl => "link card url",
+            MatchSource::CardTitle => "link card title",
+            MatchSource::CardDescription => "link card description",
         }
+    }
 
-        self.toronto_uris.put(toronto_post.uri.clone(), ());
+    /// The name stored in the posts table's match_field column
+    pub fn key(&self) -> &'static str {
+        match self {
+            MatchSource::Text => "text",
+            MatchSource::Hashtag => "hashtag",
+            MatchSource::Link => "link",
+            MatchSource::Tag => "tag",
+            MatchSource::ImageAlt => "image_alt",
+            MatchSource::VideoAlt => "video_alt",
+            MatchSource::CardUrl => "card_url",
+            MatchSource::CardTitle => "card_title",
+            MatchSource::CardDescription => "card_description",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeywordMatch {
+    /// Every keyword or rule name that matched, in rule file order. source and
+    /// snippet are for the first one.
+    pub rules: Vec<String>,
+    pub source: MatchSource,
+    pub snippet: String,
+}
+
+/// Why a post was let into the feed
+pub enum Inclusion {
+    Keyword(KeywordMatch),
+    /// Quotes this post in the feed
+    Quote(String),
+    /// Replies in the thread of this post in the feed
+    Thread(String),
+}
+
+impl std::fmt::Display for Inclusion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Inclusion::Keyword(m) => write!(f, "\"{}\" in {}", m.rules[0], m.source.as_str()),
+            Inclusion::Quote(_) => f.write_str("quotes a feed post"),
+            Inclusion::Thread(_) => f.write_str("replies in a feed thread"),
+        }
+    }
+}
+
+impl From<Inclusion> for MatchReason {
+    fn from(inclusion: Inclusion) -> Self {
+        match inclusion {
+            Inclusion::Keyword(m) => MatchReason { rules: m.rules, field: m.source.key().to_string(), snippet: m.snippet },
+            Inclusion::Quote(uri) => MatchReason { rules: Vec::new(), field: "quote".to_string(), snippet: uri },
+            Inclusion::Thread(uri) => MatchReason { rules: Vec::new(), field: "reply".to_string(), snippet: uri },
+        }
+    }
+}
+
+/// Calls visit with every piece of text in a post that keywords are matched
+/// against, as written. Hashtags and the post's own tags come before the text,
+/// so a "#toronto" is recorded as a hashtag match even though it's in the text too.
+fn visit_fields<'a>(post: &'a Post, mut visit: impl FnMut(MatchSource, &'a str)) {
+    let features = || post.facets.iter().flatten().flat_map(|facet| &facet.features);
+
+    for feat in features() {
+        if let Feature::Tag { tag } = feat {
+            visit(MatchSource::Hashtag, tag);
+        }
+    }
+
+    for tag in post.tags.iter().flatten() {
+        visit(MatchSource::Tag, tag);
+    }
+
+    visit(MatchSource::Text, &post.text);
+
+    for feat in features() {
+        if let Feature::Link { uri } = feat {
+            visit(MatchSource::Link, uri);
+        }
+    }
+
+    match post.media() {
+        Some(Embed::Images { images }) => {
+            for alt in images.iter().filter_map(|image| image.alt.as_deref()) {
+                visit(MatchSource::ImageAlt, alt);
+            }
+        },
+        Some(Embed::Video { alt: Some(alt_text) }) => {
+            visit(MatchSource::VideoAlt, alt_text);
+        },
+        Some(Embed::External { external }) => {
+            visit(MatchSource::CardUrl, &external.uri);
+            visit(MatchSource::CardTitle, &external.title);
+            visit(MatchSource::CardDescription, &external.description);
+        },
+        _ => {}
+    }
+}
+
+/// The fields from visit_fields, lowercased
+pub fn match_fields(post: &Post) -> Vec<(MatchSource, String)> {
+    let mut fields = Vec::new();
+    visit_fields(post, |source, text| fields.push((source, text.to_lowercase())));
+    fields
+}
+
+/// The text based part of matching, returning every keyword or rule that
+/// matched and where the first one did. Doesn't touch any Filter state, so
+/// it's safe to run on any thread.
+pub fn match_post(matcher: &Matcher, post: &Post) -> Option<KeywordMatch> {
+    // Nearly every post stops here, after one scan of each field and without
+    // allocating anything
+    let mut candidates = Candidates::default();
+    visit_fields(post, |_, text| matcher.scan(text, &mut candidates));
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let fields = match_fields(post);
+    let tokenized = tokenize(&fields);
+
+    let mut matches = matcher.matches(&tokenized, &candidates);
+    let first = matches.next()?;
+    let mut names = vec![first.rule.to_string()];
+    names.extend(matches.map(|m| m.rule.to_string()));
+
+    Some(KeywordMatch { rules: names, source: first.source, snippet: first.snippet })
+}
+
+/// match_post with the rules in effect right now
+pub fn matches_keywords(post: &Post) -> Option<KeywordMatch> {
+    match_post(&rules::current().matcher, post)
+}
+
+/// The exclusion rule that keeps a matching post out of the feed, if any.
+/// Only run on posts that would otherwise be included, so it can afford to
+/// redo match_fields.
+pub fn excluded_by(did: &str, post: &Post) -> Option<String> {
+    let rules = rules::current();
+    rules.exclusions.find(did, &match_fields(post)).map(String::from)
+}
+
+pub fn is_nsfw(post: &Post) -> bool {
+    if let Some(labels) = &post.labels {
+        let rules = rules::current();
+        return labels.values.iter().any(|l| rules.nsfw_labels.contains(&l.val));
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Uses the built-in rules.json, which excludes "crypto spam"
+    const CID: &str = "bafyreigks6arfsq3xxfpvqrrwonchxcnu6do76auprhhfomao6c273sixm";
+
+    fn post(mut record: serde_json::Value) -> Post {
+        record["createdAt"] = json!("2025-10-09T08:53:20.000Z");
+        serde_json::from_value(record).unwrap()
+    }
+
+    fn filter() -> Filter {
+        Filter::new(Database::new(":memory:"))
+    }
+
+    /// Runs a post through the filter like a firehose commit, and flushes
+    fn ingest(filter: &mut Filter, uri: &str, record: serde_json::Value) {
+        let (repo, path) = uri.strip_prefix("at://").unwrap().split_once('/').unwrap();
+        filter.callback(repo, path, &CID.parse().unwrap(), &post(record));
+        assert!(filter.flush(None));
+    }
+
+    #[test]
+    fn exclusions_apply_to_matching_posts() {
+        for (record, excluded) in [
+            (json!({ "text": "Toronto airdrop, claim your tokens" }), Some("crypto spam")),
+            (json!({ "text": "memecoins are huge in toronto" }), Some("crypto spam")),
+            (json!({ "text": "toronto", "tags": ["presale"] }), Some("crypto spam")),
+            (json!({ "text": "Patio season in Toronto" }), None),
+        ] {
+            assert_eq!(excluded_by("did:plc:someone", &post(record.clone())).as_deref(), excluded, "{}", record);
+        }
+    }
+
+    #[test]
+    fn suppressed_posts_are_counted_not_stored() {
+        let mut filter = filter();
+        let spam = ["at://did:plc:spam/app.bsky.feed.post/1", "at://did:plc:spam/app.bsky.feed.post/2"];
+        let fine = "at://did:plc:someone/app.bsky.feed.post/1";
+
+        ingest(&mut filter, spam[0], json!({ "text": "Toronto airdrop, claim your tokens" }));
+        assert_eq!(filter.db.get_suppressions().get("crypto spam"), Some(&(1, spam[0].to_string())));
+        ingest(&mut filter, spam[1], json!({ "text": "toronto presale starts now" }));
+        ingest(&mut filter, fine, json!({ "text": "Patio season in Toronto" }));
+
+        assert_eq!(filter.db.get_suppressions().get("crypto spam"), Some(&(2, spam[1].to_string())));
+        assert_eq!(filter.db.get_suppressions().len(), 1);
+        assert!(!filter.db.has_post(spam[0]) && !filter.db.has_post(spam[1]));
+        assert!(filter.db.has_post(fine));
+    }
+
+    #[test]
+    fn posts_without_a_match_are_not_suppressed() {
+        let mut filter = filter();
+        ingest(&mut filter, "at://did:plc:spam/app.bsky.feed.post/1", json!({ "text": "crypto airdrop, claim your tokens" }));
+        assert!(filter.db.get_suppressions().is_empty());
+    }
+
+    fn reply(text: &str, parent: &str, root: &str) -> serde_json::Value {
+        json!({
+            "text": text,
+            "reply": { "parent": { "uri": parent, "cid": CID }, "root": { "uri": root, "cid": CID } },
+        })
+    }
+
+    #[test]
+    fn reply_modes() {
+        let root = "at://did:plc:op/app.bsky.feed.post/root";
+        let elsewhere = "at://did:plc:other/app.bsky.feed.post/1";
+        let (plain, on_topic, under_root) = (
+            "at://did:plc:fan/app.bsky.feed.post/1",
+            "at://did:plc:fan/app.bsky.feed.post/2",
+            "at://did:plc:fan/app.bsky.feed.post/3",
+        );
+
+        // Which of the replies end up in the feed in each mode
+        for (mode, expected) in [
+            (ReplyMode::Include, [true, true, true]),
+            (ReplyMode::Count, [false, true, false]),
+            (ReplyMode::Exclude, [false, false, false]),
+        ] {
+            let mut filter = filter();
+            filter.reply_mode = mode;
+            ingest(&mut filter, root, json!({ "text": "Patio season in Toronto" }));
+            ingest(&mut filter, plain, reply("agreed", root, root));
+            ingest(&mut filter, on_topic, reply("best patio in Toronto is on Ossington", root, root));
+            // Only the root is in the feed
+            ingest(&mut filter, under_root, reply("same", elsewhere, root));
+
+            let included = [plain, on_topic, under_root].map(|uri| filter.db.has_post(uri));
+            assert_eq!(included, expected, "{:?}", [plain, on_topic, under_root]);
+        }
+
+        let mut filter = filter();
+        filter.reply_mode = ReplyMode::Include;
+        ingest(&mut filter, root, json!({ "text": "Patio season in Toronto" }));
+        ingest(&mut filter, plain, reply("agreed", root, root));
+        let reason = filter.db.get_match_reason(plain).unwrap();
+        assert_eq!((reason.field.as_str(), reason.snippet.as_str()), ("reply", root));
+    }
+
+    #[test]
+    fn quotes_of_feed_posts_join_the_feed() {
+        let mut filter = filter();
+        let quoted = "at://did:plc:op/app.bsky.feed.post/1";
+        // Stored behind the filter's back, like a backfilled post or one from
+        // before a restart that the warm cache didn't pick up
+        let stored = |uri: &str| Write::InsertPost(TorontoPost {
+            uri: uri.to_string(),
+            cid: CID.to_string(),
+            did: uri.trim_start_matches("at://").split('/').next().unwrap().to_string(),
+            indexed_at: 1_700_000_000,
+            created_at: 1_700_000_000,
+            reply_parent: None,
+            reply_root: None,
+            reason: MatchReason::default(),
+        });
+        filter.db.apply_writes(&[stored(quoted)], None).unwrap();
+
+        let record = |uri: &str| json!({ "$type": "app.bsky.embed.record", "record": { "uri": uri, "cid": CID } });
+        let with_media = |uri: &str| json!({
+            "$type": "app.bsky.embed.recordWithMedia",
+            "record": { "record": { "uri": uri, "cid": CID } },
+            "media": { "$type": "app.bsky.embed.images", "images": [] },
+        });
+        let elsewhere = "at://did:plc:other/app.bsky.feed.post/1";
+        for (uri, embed, included) in [
+            ("at://did:plc:fan/app.bsky.feed.post/1", record(quoted), true),
+            ("at://did:plc:fan/app.bsky.feed.post/2", with_media(quoted), true),
+            ("at://did:plc:fan/app.bsky.feed.post/3", record(elsewhere), false),
+            ("at://did:plc:fan/app.bsky.feed.post/4", with_media(elsewhere), false),
+        ] {
+            ingest(&mut filter, uri, json!({ "text": "this", "embed": embed }));
+            assert_eq!(filter.db.has_post(uri), included, "{}", embed);
+        }
+        let reason = filter.db.get_match_reason("at://did:plc:fan/app.bsky.feed.post/1").unwrap();
+        assert_eq!((reason.field.as_str(), reason.snippet.as_str()), ("quote", quoted));
+
+        // A quote of a post deleted earlier in the same batch doesn't get in
+        let late = "at://did:plc:fan/app.bsky.feed.post/5";
+        filter.on_post_deleted(quoted);
+        filter.callback("did:plc:fan", "app.bsky.feed.post/5", &CID.parse().unwrap(), &post(json!({ "text": "this", "embed": record(quoted) })));
+        assert!(filter.flush(None));
+        assert!(!filter.db.has_post(late));
+
+        // Quotes still get in once the quoted post does
+        filter.db.apply_writes(&[stored(elsewhere)], None).unwrap();
+        ingest(&mut filter, "at://did:plc:fan/app.bsky.feed.post/6", json!({ "text": "this", "embed": record(elsewhere) }));
+        assert!(filter.db.has_post("at://did:plc:fan/app.bsky.feed.post/6"));
     }
 }
@@ -0,0 +1,223 @@
+use crate::matcher::{Context, Matcher};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// The gazetteer we ship with, see the README for the format
+const GAZETTEER: &str = include_str!("../gazetteer.json");
+
+/// How close context has to be to a highly ambiguous name, in words
+const NEARBY_WORDS: usize = 8;
+
+/// How likely a name is to mean something other than the Toronto place
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Ambiguity {
+    /// Hardly ever anything else, e.g. "Leslieville". Matched on its own.
+    #[default]
+    Low,
+    /// Also somewhere else, e.g. "Parkdale". Needs Toronto context anywhere
+    /// in the post.
+    Medium,
+    /// Usually an everyday word, e.g. "Junction". Needs Toronto context
+    /// within NEARBY_WORDS of it.
+    High,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GazetteerFile {
+    /// Words that give an ambiguous name away as being in Toronto. Every
+    /// low ambiguity name counts too.
+    context: Vec<String>,
+    neighbourhoods: Vec<Entry>,
+    stations: Vec<Entry>,
+    streets: Vec<Entry>,
+    places: Vec<Entry>,
+}
+
+/// A low ambiguity name, or a place with its ambiguity and other names
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Name(String),
+    Place(Place),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Place {
+    name: String,
+    #[serde(default)]
+    ambiguity: Ambiguity,
+    /// Each with its own ambiguity, low unless given
+    #[serde(default)]
+    aliases: Vec<Entry>,
+}
+
+/// Toronto neighbourhoods, stations, streets, parks and venues, loaded into
+/// the matcher alongside the rule file's keywords
+pub struct Gazetteer {
+    context: Vec<String>,
+    names: Vec<(String, Ambiguity)>,
+}
+
+impl Gazetteer {
+    pub fn builtin() -> Result<Gazetteer, String> {
+        Gazetteer::parse(GAZETTEER).map_err(|e| format!("gazetteer: {}", e))
+    }
+
+    fn parse(json: &str) -> Result<Gazetteer, String> {
+        let file: GazetteerFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+        let mut names = Vec::new();
+        let entries = file.neighbourhoods.iter().chain(&file.stations).chain(&file.streets).chain(&file.places);
+        for entry in entries {
+            match entry {
+                Entry::Name(name) => names.push((name.clone(), Ambiguity::Low)),
+                Entry::Place(place) => {
+                    names.push((place.name.clone(), place.ambiguity));
+                    for alias in &place.aliases {
+                        match alias {
+                            Entry::Name(name) => names.push((name.clone(), Ambiguity::Low)),
+                            Entry::Place(Place { name, ambiguity, aliases }) if aliases.is_empty() => {
+                                names.push((name.clone(), *ambiguity));
+                            },
+                            Entry::Place(alias) => return Err(format!("alias {:?} has aliases of its own", alias.name)),
+                        }
+                    }
+                },
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for (name, _) in &names {
+            if name.trim().is_empty() || name.trim() != name {
+                return Err(format!("{:?} is empty or has leading or trailing whitespace", name));
+            }
+            if !seen.insert(key(name)) {
+                return Err(format!("{:?} is listed twice", name));
+            }
+        }
+
+        Ok(Gazetteer { context: file.context, names })
+    }
+
+    /// Adds every name to the matcher, except ones that are already strict
+    /// keywords, and returns how many it added. The keywords count as
+    /// context for the ambiguous names, apart from *substring* ones.
+    pub fn add_to(&self, matcher: &mut Matcher, keywords: &[String]) -> Result<usize, String> {
+        let keys: HashSet<String> = keywords.iter().map(|keyword| key(keyword)).collect();
+        let names: Vec<&(String, Ambiguity)> = self.names.iter()
+            .filter(|(name, _)| !keys.contains(&key(name)))
+            .collect();
+
+        let words = keywords.iter().filter(|keyword| !(keyword.starts_with('*') && keyword.ends_with('*')));
+        let unambiguous = names.iter()
+            .filter(|(_, ambiguity)| *ambiguity == Ambiguity::Low)
+            .map(|(name, _)| name.as_str());
+        let context = Context::new(self.context.iter().chain(words).map(String::as_str).chain(unambiguous))
+            .map(Arc::new)
+            .map_err(|e| format!("gazetteer: {}", e))?;
+
+        for (name, ambiguity) in &names {
+            match ambiguity {
+                Ambiguity::Low => matcher.add_words(name),
+                Ambiguity::Medium => matcher.add_words_in_context(name, &context, None),
+                Ambiguity::High => matcher.add_words_in_context(name, &context, Some(NEARBY_WORDS)),
+            }.map_err(|e| format!("gazetteer: {}", e))?;
+        }
+
+        Ok(names.len())
+    }
+}
+
+/// What a name matches on, so "St. Clair" and "st clair" are the same name
+fn key(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::MatchSource;
+    use crate::matcher::{tokenize, Candidates};
+
+    /// The built-in gazetteer on its own, as if the rule file had no keywords
+    fn matcher() -> Matcher {
+        let mut matcher = Matcher::default();
+        Gazetteer::builtin().unwrap().add_to(&mut matcher, &[]).unwrap();
+        matcher.finish();
+        matcher
+    }
+
+    /// The names that match text, going through the prefilter like match_post
+    fn matched(matcher: &Matcher, text: &str) -> Vec<String> {
+        let mut candidates = Candidates::default();
+        matcher.scan(text, &mut candidates);
+        let fields = [(MatchSource::Text, text.to_lowercase())];
+        let tokenized = tokenize(&fields);
+        matcher.matches(&tokenized, &candidates).map(|m| m.rule.to_string()).collect()
+    }
+
+    #[test]
+    fn unambiguous_names_match_on_their_own() {
+        let matcher = matcher();
+        assert_eq!(matched(&matcher, "Brunch in Leslieville this morning"), ["Leslieville"]);
+        assert_eq!(matched(&matcher, "king and spadina"), ["King", "Spadina"]);
+    }
+
+    #[test]
+    fn generic_names_need_context() {
+        let matcher = matcher();
+        for text in [
+            "Blake Jones scored a goal",
+            "the junction area of the circuit board",
+            "North York Moors national park",
+            "King Street West in Seattle",
+            "Queen St W and 2nd Ave",
+            "coffee in the Junction",
+        ] {
+            assert!(matched(&matcher, text).is_empty(), "{:?} matched {:?}", text, matched(&matcher, text));
+        }
+    }
+
+    #[test]
+    fn generic_names_match_with_context() {
+        let matcher = matcher();
+        for (text, name) in [
+            ("Blake-Jones has great pho by the streetcar stop", "Blake-Jones"),
+            ("Junction Area rents are up again, thanks TTC", "Junction Area"),
+            ("Moving to North York next month, where do Leafs fans watch games?", "North York"),
+            ("Patio season on King Street West, the 504 streetcar is packed", "King Street West"),
+            ("just moved to the Junction, the TTC is so much better", "The Junction"),
+        ] {
+            assert!(matched(&matcher, text).iter().any(|m| m == name), "{:?} didn't match {:?}", text, name);
+        }
+    }
+
+    #[test]
+    fn high_ambiguity_context_has_to_be_nearby() {
+        let matcher = matcher();
+        let far = "The Junction is where it happened. Somebody left a bag on a bench for hours, and later it went to the TTC";
+        assert!(!matched(&matcher, far).iter().any(|m| m == "The Junction"));
+    }
+
+    #[test]
+    fn rejects_aliases_with_aliases() {
+        let json = r#"{"context": [], "neighbourhoods": [
+            {"name": "A", "aliases": [{"name": "B", "aliases": ["C"]}]}
+        ], "stations": [], "streets": [], "places": []}"#;
+        assert!(Gazetteer::parse(json).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let json = r#"{"context": [], "neighbourhoods": ["St. Clair"], "stations": [], "streets": ["st clair"], "places": []}"#;
+        assert!(Gazetteer::parse(json).is_err());
+    }
+}
@@ -1,127 +1,397 @@
-use crate::parser::{parse_car_blocks, parse_message};
-use tungstenite::{connect, Message};
+use crate::parser::{parse_car_blocks, parse_message, ParseError};
+use tungstenite::{connect, Message, WebSocket};
 use tungstenite::stream::MaybeTlsStream;
-use crate::models::{Post, Action, Like, Repost, InteractionType};
+use std::net::TcpStream;
+use crate::models::{Post, Action, Like, Repost, InteractionType, Frame, FirehoseMessage, Cid};
 use crate::db::Metadata;
-use crate::filter::Filter;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::filter::{matches_keywords, Event, Filter};
+use crate::pipeline::{self, QueueStats};
+use crate::relay::{report_gap, RelayPool};
+use crate::capture::{CaptureReader, CaptureWriter};
+use crate::verify::Verifier;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
 
 const READ_TIMEOUT_SECS: u64 = 90;
-const MAX_CURSOR_AGE_SECS: i64 = 259200; // 3 days
+
+/// Sets a read timeout so we detect dead connections instead of
+/// hanging forever on socket.read()
+pub fn set_read_timeout(socket: &WebSocket<MaybeTlsStream<TcpStream>>) {
+    let timeout = Some(Duration::from_secs(READ_TIMEOUT_SECS));
+    match socket.get_ref() {
+        MaybeTlsStream::NativeTls(tls) => {
+            tls.get_ref().set_read_timeout(timeout).ok();
+        },
+        MaybeTlsStream::Plain(tcp) => {
+            tcp.set_read_timeout(timeout).ok();
+        },
+        _ => {
+            eprintln!("[Ingestion] Warning: could not set read timeout on stream");
+        }
+    }
+}
+
+/// "resume" (default) picks up from the saved cursor on startup, "fresh" ignores it
+pub fn startup_mode() -> String {
+    std::env::var("TOSKY_STARTUP").unwrap_or_else(|_| "resume".to_string())
+}
+
+/// Unix seconds for an event's RFC 3339 time, or now if it won't parse
+pub fn event_time(time: &str) -> i64 {
+    chrono::DateTime::parse_from_rfc3339(time)
+        .map(|dt| dt.timestamp())
+        .unwrap_or_else(|_| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64)
+}
+
+/// Tracks the latest seq we've handled so it can be committed in the same
+/// transaction as the writes it produced. A crash can then never keep the
+/// posts/interactions but lose the cursor, or vice versa.
+pub struct CursorTracker {
+    key: String,
+    latest: Option<Metadata>,
+}
+
+impl CursorTracker {
+    pub fn new(key: &str) -> Self {
+        CursorTracker { key: key.to_string(), latest: None }
+    }
+
+    pub fn advance(&mut self, seq: i64, time: i64) {
+        self.latest = Some(Metadata { seq, last_updated: time });
+    }
+
+    /// Commits the filter's queued writes together with the latest cursor.
+    /// The cursor is kept for the next try if the commit fails.
+    pub fn flush(&mut self, filter: &mut Filter) {
+        let latest = self.latest.take();
+        if !filter.flush(latest.as_ref().map(|meta| (self.key.as_str(), meta))) {
+            self.latest = latest;
+        }
+    }
+}
+
+pub enum FrameOutcome {
+    /// Handled an event, carrying its seq and time for the cursor
+    Processed { seq: i64, time: i64 },
+    /// Not something we care about
+    Skipped,
+    /// The relay can't serve from our cursor, drop it and start fresh.
+    /// outdated is true if the cursor fell out of the relay's retention
+    /// window, false if it's ahead of anything the relay has seen.
+    ResetCursor { outdated: bool },
+    /// The relay is about to drop us, reconnect and resume
+    Reconnect,
+    /// The frame didn't decode, skip it
+    Malformed(ParseError),
+}
+
+/// A frame after the decode workers are done with it
+pub struct Decoded {
+    pub outcome: FrameOutcome,
+    pub events: Vec<Event>,
+}
+
+impl Decoded {
+    fn outcome(outcome: FrameOutcome) -> Self {
+        Decoded { outcome, events: Vec::new() }
+    }
+}
+
+/// Decodes one binary firehose frame into the events it holds for the filter.
+/// Runs on the decode workers, so it can't touch Filter or the database.
+pub fn decode_frame(verifier: &Verifier, data: &[u8]) -> Decoded {
+    let message = match parse_message(data) {
+        Ok(Some(message)) => message,
+        Ok(None) => return Decoded::outcome(FrameOutcome::Skipped),
+        Err(e) => return Decoded::outcome(FrameOutcome::Malformed(e)),
+    };
+
+    match message {
+        FirehoseMessage::Commit(frame) => {
+            let mut events = Vec::new();
+            match decode_commit(verifier, &frame, &mut events) {
+                Ok(()) => Decoded {
+                    outcome: FrameOutcome::Processed { seq: frame.seq, time: event_time(&frame.time) },
+                    events,
+                },
+                Err(e) => Decoded::outcome(FrameOutcome::Malformed(e)),
+            }
+        },
+        // The cached signing key is dropped by the writer, see pipeline::run
+        FirehoseMessage::Identity(identity) => Decoded {
+            outcome: FrameOutcome::Processed { seq: identity.seq, time: event_time(&identity.time) },
+            events: vec![Event::Identity { did: identity.did, handle: identity.handle }],
+        },
+        FirehoseMessage::Account(account) => Decoded {
+            outcome: FrameOutcome::Processed { seq: account.seq, time: event_time(&account.time) },
+            events: vec![Event::AccountStatus { did: account.did, active: account.active, status: account.status }],
+        },
+        FirehoseMessage::Info(info) => {
+            println!("[Ingestion] Relay info {}: {}", info.name, info.message.unwrap_or_default());
+            Decoded::outcome(if info.name == "OutdatedCursor" {
+                FrameOutcome::ResetCursor { outdated: true }
+            } else {
+                FrameOutcome::Skipped
+            })
+        },
+        FirehoseMessage::Error(err) => {
+            eprintln!("[Ingestion] Relay error {}: {}", err.error, err.message.unwrap_or_default());
+            Decoded::outcome(match err.error.as_str() {
+                // Our cursor is ahead of what this relay has seen (e.g. it was
+                // reset), resuming from it would never deliver anything
+                "FutureCursor" => FrameOutcome::ResetCursor { outdated: false },
+                // ConsumerTooSlow and anything else: the relay hangs up after an error frame
+                _ => FrameOutcome::Reconnect,
+            })
+        },
+    }
+}
+
+fn decode_commit(verifier: &Verifier, frame: &Frame, events: &mut Vec<Event>) -> Result<(), ParseError> {
+    // Decoded once per frame, not per op. A bad CAR fails the whole frame
+    // before any of its ops become events.
+    let blocks = parse_car_blocks(&frame.blocks)?;
+    if !verifier.commits.check(frame, &blocks) {
+        return Ok(());
+    }
+
+    for op in &frame.ops {
+        if matches!(op.action, Action::Delete) {
+            if op.path.starts_with("app.bsky.feed.post/") {
+                events.push(Event::PostDeleted(format!("at://{}/{}", frame.repo, op.path)));
+            } else if let Some((kind, rkey)) = InteractionType::from_path(&op.path) {
+                events.push(Event::InteractionDeleted { actor_did: frame.repo.clone(), rkey: rkey.to_string(), kind });
+            }
+            continue;
+        }
+
+        if !matches!(op.action, Action::Create) {
+            continue;
+        }
+        if let Some(target_cid) = op.cid.as_deref().and_then(|cid| Cid::from_tag42(cid).ok()) {
+            if let Some(block_data) = blocks.get(target_cid.as_bytes()) {
+                if !verifier.blocks.check(&target_cid, block_data) {
+                    continue;
+                }
+
+                if op.path.starts_with("app.bsky.feed.post/") {
+                    match serde_cbor::from_slice::<Post>(block_data) {
+                        Ok(post) => events.push(Event::Post {
+                            repo: frame.repo.clone(),
+                            path: op.path.clone(),
+                            cid: target_cid,
+                            keyword_match: matches_keywords(&post),
+                            post: Box::new(post),
+                        }),
+                        Err(e) => println!("Failed to parse post: {}", e),
+                    }
+                } else if let Some((interaction_type, rkey)) = InteractionType::from_path(&op.path) {
+                    let subject = match interaction_type {
+                        InteractionType::LIKE => serde_cbor::from_slice::<Like>(block_data).map(|like| like.subject),
+                        InteractionType::REPOST => serde_cbor::from_slice::<Repost>(block_data).map(|repost| repost.subject),
+                    };
+
+                    match subject {
+                        Ok(subject) => events.push(Event::Interaction {
+                            actor_did: frame.repo.clone(),
+                            rkey: rkey.to_string(),
+                            subject,
+                            kind: interaction_type,
+                        }),
+                        Err(e) => println!("Failed to parse {}: {}", interaction_type.as_str(), e),
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Feeds a capture file through the same decode -> filter -> DB path as live
+/// ingestion. speed is a multiplier on the recorded pacing; 0 replays as fast
+/// as possible, which doubles as a throughput benchmark.
+pub fn start_replay(filter: &mut Filter, path: &str, speed: f64) {
+    let reader = match CaptureReader::open(path) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("[Replay] Could not open capture {}: {}", path, e);
+            return;
+        }
+    };
+
+    println!("[Replay] Replaying {} at {} with {} decode workers", path,
+        if speed > 0.0 { format!("{}x", speed) } else { String::from("full speed") },
+        pipeline::worker_count());
+
+    let started = Instant::now();
+    let mut first_ts: Option<u64> = None;
+    let mut bytes = 0u64;
+    let mut frames = 0u64;
+    let mut events = 0u64;
+    let mut bad_frames = 0u64;
+    let verifier = Verifier::from_env();
+    let mut records = reader;
+
+    let next_frame = || {
+        let (ts, data) = match records.next()? {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("[Replay] Truncated capture, stopping: {}", e);
+                return None;
+            }
+        };
+
+        if speed > 0.0 {
+            let first = *first_ts.get_or_insert(ts);
+            let due = Duration::from_secs_f64(ts.saturating_sub(first) as f64 / 1000.0 / speed);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+
+        bytes += data.len() as u64;
+        Some(data)
+    };
+
+    let stats = pipeline::run(filter, &verifier, next_frame, |filter, outcome| {
+        frames += 1;
+        match outcome {
+            FrameOutcome::Processed { .. } => events += 1,
+            FrameOutcome::Malformed(e) => {
+                bad_frames += 1;
+                eprintln!("[Replay] Skipping malformed frame {}: {}", frames, e);
+            },
+            _ => {},
+        }
+        if filter.needs_flush() {
+            filter.flush(None);
+        }
+
+        if frames.is_multiple_of(10_000) {
+            println!("[Replay] {} frames ({:.0} frames/s)", frames, frames as f64 / started.elapsed().as_secs_f64());
+        }
+        true
+    });
+
+    filter.flush(None);
+
+    let elapsed = started.elapsed().as_secs_f64();
+    println!("[Replay] Done. {} frames ({} events, {} malformed, {:.1} MB) in {:.2}s: {:.0} frames/s, {:.1} MB/s",
+        frames, events, bad_frames, bytes as f64 / 1_000_000.0, elapsed,
+        frames as f64 / elapsed, bytes as f64 / 1_000_000.0 / elapsed);
+    report_queues(&stats, frames);
+    if filter.db.dropped_writes() > 0 {
+        println!("[Replay] Dropped {} writes that failed", filter.db.dropped_writes());
+    }
+    let blocks_checked = verifier.blocks.checked.load(Ordering::Relaxed);
+    if blocks_checked > 0 {
+        println!("[Replay] Verified {} blocks, {} mismatches",
+            blocks_checked, verifier.blocks.mismatches.load(Ordering::Relaxed));
+    }
+    if verifier.commits.enabled() {
+        println!("[Replay] Verified {} commits, {} failures",
+            verifier.commits.verified.load(Ordering::Relaxed), verifier.commits.failures.load(Ordering::Relaxed));
+    }
+}
+
+fn report_queues(stats: &QueueStats, frames: u64) {
+    // Decode + verify + keyword matching on the workers, excluding DB writes and reads
+    println!("[Replay] Per-frame decode cost: {:.1}µs (summed across workers)",
+        stats.decode_nanos.load(Ordering::Relaxed) as f64 / 1000.0 / frames.max(1) as f64);
+    println!("[Replay] Peak queue depth: {} frames to decode, {} to write",
+        stats.max_decode.load(Ordering::Relaxed), stats.max_write.load(Ordering::Relaxed));
+}
+
+/// A second handle on the socket's TCP stream, so the writer can shut it down
+/// and unblock a read in progress on the reader thread
+fn tcp_handle(socket: &WebSocket<MaybeTlsStream<TcpStream>>) -> Option<TcpStream> {
+    match socket.get_ref() {
+        MaybeTlsStream::NativeTls(tls) => tls.get_ref().try_clone().ok(),
+        MaybeTlsStream::Plain(tcp) => tcp.try_clone().ok(),
+        _ => None,
+    }
+}
 
 pub fn start_ingestion(filter: &mut Filter) {
-    let mut first_connect = true;
+    let mut first_connect = startup_mode() == "fresh";
+
+    // Optionally record raw frames so they can be replayed offline later
+    let mut capture = std::env::var("TOSKY_CAPTURE_PATH").ok().and_then(|path| {
+        match CaptureWriter::open(&path) {
+            Ok((writer, torn)) => {
+                if torn > 0 {
+                    eprintln!("[Ingestion] Cut {} bytes of a partly written record off the end of {}", torn, path);
+                }
+                println!("[Ingestion] Recording frames to {}", path);
+                Some(writer)
+            },
+            Err(e) => {
+                eprintln!("[Ingestion] Could not open capture file {}: {}", path, e);
+                None
+            }
+        }
+    });
+
+    let mut relays = RelayPool::from_env();
+    let mut bad_frames = 0u64;
+    let verifier = Verifier::from_env();
 
     loop {
-        let uri = if first_connect {
+        let resume_from = if first_connect {
             first_connect = false;
-            println!("[Ingestion] Starting fresh (post-backfill)");
-            String::from("wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos")
+            println!("[Ingestion] Starting fresh (TOSKY_STARTUP=fresh)");
+            None
         } else {
-            match filter.db.get_metadata() {
-                Some(meta) => {
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs() as i64;
-                    let age = now - meta.last_updated;
-
-                    if meta.last_updated > 0 && age > MAX_CURSOR_AGE_SECS {
-                        println!("[Ingestion] Cursor is {}s old (>{} max), discarding stale cursor",
-                            age, MAX_CURSOR_AGE_SECS);
-                        String::from("wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos")
-                    } else {
-                        println!("[Ingestion] Resuming from cursor: {} (age: {}s)", meta.seq, age);
-                        format!("wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos?cursor={}", meta.seq)
-                    }
-                },
-                None => {
-                    println!("[Ingestion] No cursor found, starting fresh");
-                    String::from("wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos")
-                },
-            }
+            relays.select(&filter.db)
         };
 
+        let uri = relays.current().subscribe_url(resume_from);
+        let mut cursor = CursorTracker::new(&relays.current().cursor_key());
+
         match connect(&uri) {
             Ok((mut socket, _)) => {
-                println!("[Ingestion] Connected to the firehose");
-
-                // Set read timeout so we detect dead connections instead of
-                // hanging forever on socket.read()
-                let timeout = Some(Duration::from_secs(READ_TIMEOUT_SECS));
-                match socket.get_ref() {
-                    MaybeTlsStream::NativeTls(tls) => {
-                        tls.get_ref().set_read_timeout(timeout).ok();
-                    },
-                    MaybeTlsStream::Plain(tcp) => {
-                        tcp.set_read_timeout(timeout).ok();
-                    },
-                    _ => {
-                        eprintln!("[Ingestion] Warning: could not set read timeout on stream");
-                    }
-                }
+                println!("[Ingestion] Connected to {} ({} decode workers)",
+                    relays.current().url, pipeline::worker_count());
+
+                set_read_timeout(&socket);
+                let interrupt = tcp_handle(&socket);
 
-                let mut count = 0;
+                let mut healthy = false;
+                let mut read_failed = false;
+                let mut interrupted = false;
 
-                // when we receive a message from the url, call a provided callback
-                // that passes the posts to our filter
-                loop {
+                // The reader thread only pulls frames off the socket (and
+                // records them), everything else happens further down the pipeline
+                let next_frame = || loop {
                     let msg = match socket.read() {
                         Ok(msg) => msg,
                         Err(e) => {
                             eprintln!("[Ingestion] WebSocket read error: {}", e);
-                            break;
+                            read_failed = true;
+                            return None;
                         }
                     };
 
                     match msg {
                         Message::Binary(data) => {
-                            if let Ok(Some(frame)) = parse_message(&data) {
-                                if count > 500 {
-                                    count = 0;
-                                    filter.db.set_metadata(&Metadata { seq: frame.seq, last_updated: 0 });
-                                } else {
-                                    count += 1;
-                                }
-
-                                for op in &frame.ops {
-                                    if !matches!(op.action, Action::Create) {
-                                        continue;
-                                    }
-                                    let blocks = parse_car_blocks(&frame.blocks);
-
-                                    if let Some(target_cid) = &op.cid {
-                                        for (block_cid, block_data) in &blocks {
-                                            if block_cid != &target_cid[1..] {
-                                                continue;
-                                            }
-
-                                            if op.path.starts_with("app.bsky.feed.post/") {
-                                                match serde_cbor::from_slice::<Post>(&block_data) {
-                                                    Ok(post) => filter.callback(&frame, &op, &post),
-                                                    Err(e) => println!("Failed to parse post: {}", e),
-                                                }
-                                            } else if op.path.starts_with("app.bsky.feed.like/") {
-                                                match serde_cbor::from_slice::<Like>(&block_data) {
-                                                    Ok(like) => filter.on_interaction(&like.subject, InteractionType::LIKE),
-                                                    Err(e) => println!("Failed to parse like: {}", e),
-                                                }
-                                            } else if op.path.starts_with("app.bsky.feed.repost/") {
-                                                match serde_cbor::from_slice::<Repost>(&block_data) {
-                                                    Ok(repost) => filter.on_interaction(&repost.subject, InteractionType::REPOST),
-                                                    Err(e) => println!("Failed to parse repost: {}", e),
-                                                }
-                                            }
-                                        
-                                        }
-                                    }
-                                }
+                            match capture.as_mut().map(|capture| capture.write_frame(&data)) {
+                                Some(Err(e)) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                                    eprintln!("[Ingestion] Not recording frame: {}", e);
+                                },
+                                // Anything after a partly written record would be unreadable
+                                Some(Err(e)) => {
+                                    eprintln!("[Ingestion] Failed to write capture, no longer recording: {}", e);
+                                    capture = None;
+                                },
+                                _ => {},
                             }
+                            return Some(data);
                         }
                         Message::Close(_) => {
                             // Server closed connection
-                            break;
+                            return None;
                         }
                         Message::Ping(_) | Message::Pong(_) => {
                             // Heartbeat - ignore
@@ -131,14 +401,70 @@ pub fn start_ingestion(filter: &mut Filter) {
                         }
                         _ => {}
                     }
+                };
+
+                pipeline::run(filter, &verifier, next_frame, |filter, outcome| {
+                    if let FrameOutcome::Processed { seq, time } = outcome {
+                        cursor.advance(seq, time);
+                    }
+                    if filter.needs_flush() {
+                        cursor.flush(filter);
+                    }
+
+                    let keep_going = match outcome {
+                        FrameOutcome::Processed { .. } => {
+                            if !healthy {
+                                healthy = true;
+                                relays.record_success();
+                            }
+                            true
+                        },
+                        FrameOutcome::Skipped => true,
+                        FrameOutcome::Malformed(e) => {
+                            bad_frames += 1;
+                            eprintln!("[Ingestion] Skipping malformed frame ({} so far): {}", bad_frames, e);
+                            true
+                        },
+                        FrameOutcome::ResetCursor { outdated } => {
+                            println!("[Ingestion] Dropping cursor for {} and starting fresh", relays.current().url);
+                            cursor.flush(filter);
+                            // A future cursor didn't miss anything, there's no gap to report
+                            if let Some(meta) = filter.db.get_metadata(&cursor.key).filter(|_| outdated) {
+                                report_gap(&filter.db, &relays.current().url, &meta);
+                            }
+                            filter.db.delete_metadata(&cursor.key);
+                            cursor = CursorTracker::new(&cursor.key);
+                            false
+                        },
+                        FrameOutcome::Reconnect => false,
+                    };
+
+                    if !keep_going {
+                        interrupted = true;
+                        if let Some(tcp) = interrupt.as_ref() {
+                            tcp.shutdown(std::net::Shutdown::Both).ok();
+                        }
+                    }
+                    keep_going
+                });
+
+                // Shutting the socket down ourselves also fails the read, that's not the relay's fault
+                if read_failed && !interrupted {
+                    relays.record_failure();
                 }
             },
             Err(e) => {
-                eprintln!("[Ingestion] Connection failed: {}", e);
+                eprintln!("[Ingestion] Connection to {} failed: {}", relays.current().url, e);
+                relays.record_failure();
             }
-        }        
+        }
+
+        cursor.flush(filter);
+        if let Some(capture) = capture.as_mut() {
+            capture.flush().ok();
+        }
 
         println!("[Ingestion] Disconnected, reconnecting in 5s...");
         thread::sleep(Duration::from_secs(5));
     }
-}
\ No newline at end of file
+}
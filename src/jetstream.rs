@@ -0,0 +1,355 @@
+use crate::ingestion::{set_read_timeout, startup_mode, CursorTracker};
+use tungstenite::{connect, Message};
+use crate::models::{Post, Action, Like, Repost, InteractionType, JetstreamEvent, Cid};
+use crate::filter::Filter;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::thread;
+
+// Jetstream keeps roughly a day of events around
+const MAX_CURSOR_AGE_SECS: i64 = 86400;
+const CURSOR_KEY: &str = "jetstream_cursor";
+
+const WANTED_COLLECTIONS: &[&str] = &[
+    "app.bsky.feed.post",
+    "app.bsky.feed.like",
+    "app.bsky.feed.repost",
+];
+
+fn subscribe_url(base_url: &str, cursor: Option<i64>) -> String {
+    let mut url = base_url.to_string();
+
+    for (i, collection) in WANTED_COLLECTIONS.iter().enumerate() {
+        url.push(if i == 0 { '?' } else { '&' });
+        url.push_str("wantedCollections=");
+        url.push_str(collection);
+    }
+
+    if let Some(c) = cursor {
+        url.push_str(&format!("&cursor={}", c));
+    }
+
+    url
+}
+
+/// Reads Jetstream at base_url (its /subscribe endpoint) until the process exits
+pub fn start_jetstream(filter: &mut Filter, base_url: &str) {
+    let mut first_connect = startup_mode() == "fresh";
+    let mut cursor = CursorTracker::new(CURSOR_KEY);
+
+    loop {
+        let uri = if first_connect {
+            first_connect = false;
+            println!("[Jetstream] Starting fresh (TOSKY_STARTUP=fresh)");
+            subscribe_url(base_url, None)
+        } else {
+            match filter.db.get_metadata(CURSOR_KEY) {
+                Some(meta) => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs() as i64;
+                    let age = now - meta.last_updated;
+
+                    if meta.last_updated > 0 && age > MAX_CURSOR_AGE_SECS {
+                        println!("[Jetstream] Cursor is {}s old (>{} max), discarding stale cursor",
+                            age, MAX_CURSOR_AGE_SECS);
+                        subscribe_url(base_url, None)
+                    } else {
+                        println!("[Jetstream] Resuming from cursor: {} (age: {}s)", meta.seq, age);
+                        subscribe_url(base_url, Some(meta.seq))
+                    }
+                },
+                None => {
+                    println!("[Jetstream] No cursor found, starting fresh");
+                    subscribe_url(base_url, None)
+                },
+            }
+        };
+
+        stream(filter, &mut cursor, &uri);
+        cursor.flush(filter);
+
+        println!("[Jetstream] Disconnected, reconnecting in 5s...");
+        thread::sleep(Duration::from_secs(5));
+    }
+}
+
+/// Reads events from one connection until the server hangs up or a read fails
+fn stream(filter: &mut Filter, cursor: &mut CursorTracker, uri: &str) {
+    match connect(uri) {
+        Ok((mut socket, _)) => {
+            println!("[Jetstream] Connected to {}", uri);
+            set_read_timeout(&socket);
+
+            loop {
+                let msg = match socket.read() {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        eprintln!("[Jetstream] WebSocket read error: {}", e);
+                        break;
+                    }
+                };
+
+                match msg {
+                    Message::Text(text) => {
+                        let event = match serde_json::from_str::<JetstreamEvent>(text.as_str()) {
+                            Ok(event) => event,
+                            Err(e) => {
+                                println!("Failed to parse jetstream event: {}", e);
+                                continue;
+                            }
+                        };
+
+                        handle_event(filter, &event);
+                        cursor.advance(event.time_us, event.time_us / 1_000_000);
+                        if filter.needs_flush() {
+                            cursor.flush(filter);
+                        }
+                    }
+                    Message::Close(_) => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        },
+        Err(e) => {
+            eprintln!("[Jetstream] Connection failed: {}", e);
+        }
+    }
+}
+
+/// Routes a Jetstream commit into the same Filter paths the firehose uses
+fn handle_event(filter: &mut Filter, event: &JetstreamEvent) {
+    let commit = match (event.kind.as_str(), &event.commit, &event.identity, &event.account) {
+        ("commit", Some(commit), _, _) => commit,
+        ("identity", _, Some(identity), _) => {
+            filter.on_identity(&identity.did, identity.handle.as_deref());
+            return;
+        },
+        ("account", _, _, Some(account)) => {
+            filter.on_account_status(&account.did, account.active, account.status.as_deref());
+            return;
+        },
+        _ => return,
+    };
+
+    let path = format!("{}/{}", commit.collection, commit.rkey);
+
+    if matches!(commit.operation, Action::Delete) {
+        if commit.collection == "app.bsky.feed.post" {
+            filter.on_post_deleted(&format!("at://{}/{}", event.did, path));
+        } else if let Some((interaction_type, rkey)) = InteractionType::from_path(&path) {
+            filter.on_interaction_deleted(&event.did, rkey, interaction_type);
+        }
+        return;
+    }
+
+    if !matches!(commit.operation, Action::Create) {
+        return;
+    }
+
+    let (record, cid) = match (&commit.record, commit.cid.as_deref().map(str::parse::<Cid>)) {
+        (Some(record), Some(Ok(cid))) => (record.clone(), cid),
+        _ => return,
+    };
+
+    if commit.collection == "app.bsky.feed.post" {
+        match serde_json::from_value::<Post>(record) {
+            Ok(post) => filter.callback(&event.did, &path, &cid, &post),
+            Err(e) => println!("Failed to parse post: {}", e),
+        }
+    } else if let Some((interaction_type, rkey)) = InteractionType::from_path(&path) {
+        let subject = match interaction_type {
+            InteractionType::LIKE => serde_json::from_value::<Like>(record).map(|like| like.subject),
+            InteractionType::REPOST => serde_json::from_value::<Repost>(record).map(|repost| repost.subject),
+        };
+
+        match subject {
+            Ok(subject) => filter.on_interaction(&event.did, rkey, &subject, interaction_type),
+            Err(e) => println!("Failed to parse {}: {}", interaction_type.as_str(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::pipeline;
+    use crate::verify::Verifier;
+    use serde_cbor::Value;
+    use serde_json::json;
+    use std::net::TcpListener;
+
+    const RECORDED: &str = include_str!("../tests/fixtures/jetstream.jsonl");
+    const POST_URI: &str = "at://did:plc:w4xbfzo7kqfes5zb7r6qv3rw/app.bsky.feed.post/3m2qzg3a2bc2k";
+
+    fn temp_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("tosky-jetstream-{}-{}.db", std::process::id(), name));
+        std::fs::remove_file(&path).ok();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Serves the recorded events to the first client, then closes, returning
+    /// the URL the client asked for
+    // accept_hdr's callback signature is tungstenite's
+    #[allow(clippy::result_large_err)]
+    fn replay_server() -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/subscribe", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut requested = String::new();
+            let mut socket = tungstenite::accept_hdr(stream, |req: &tungstenite::handshake::server::Request, resp| {
+                requested = req.uri().to_string();
+                Ok(resp)
+            }).unwrap();
+
+            for line in RECORDED.lines() {
+                socket.send(Message::text(line)).unwrap();
+            }
+            socket.close(None).unwrap();
+            // Finish the close handshake
+            while socket.read().is_ok() {}
+            requested
+        });
+
+        (url, handle)
+    }
+
+    fn varint(mut n: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        while n >= 0x80 {
+            out.push((n as u8 & 0x7f) | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+        out
+    }
+
+    fn cbor(value: serde_json::Value) -> Value {
+        serde_cbor::value::to_value(value).unwrap()
+    }
+
+    fn frame(t: &str, body: Value) -> Vec<u8> {
+        let mut data = serde_cbor::to_vec(&cbor(json!({ "op": 1, "t": t }))).unwrap();
+        data.extend(serde_cbor::to_vec(&body).unwrap());
+        data
+    }
+
+    /// The #commit, #identity or #account frame a relay would have sent for a
+    /// recorded Jetstream event
+    fn firehose_frame(seq: i64, line: &str) -> Vec<u8> {
+        let event: serde_json::Value = serde_json::from_str(line).unwrap();
+        let kind = event["kind"].as_str().unwrap();
+        if kind != "commit" {
+            return frame(&format!("#{}", kind), cbor(event[kind].clone()));
+        }
+
+        let commit = &event["commit"];
+        let path = format!("{}/{}", commit["collection"].as_str().unwrap(), commit["rkey"].as_str().unwrap());
+        let mut op = cbor(json!({ "action": commit["operation"], "path": path, "cid": null }));
+        let mut blocks = varint(1);
+        blocks.push(0xa0);
+
+        if let Some(cid) = commit["cid"].as_str() {
+            let cid = cid.parse::<Cid>().unwrap().as_bytes().to_vec();
+            let record = serde_cbor::to_vec(&cbor(commit["record"].clone())).unwrap();
+            blocks.extend(varint(cid.len() + record.len()));
+            blocks.extend(&cid);
+            blocks.extend(record);
+
+            if let Value::Map(op) = &mut op {
+                let link = [&[0x00][..], &cid].concat();
+                op.insert(Value::Text("cid".into()), Value::Tag(42, Box::new(Value::Bytes(link))));
+            }
+        }
+
+        let mut body = cbor(json!({
+            "repo": event["did"],
+            "seq": seq,
+            "time": "2025-10-09T08:53:20.000Z",
+        }));
+        if let Value::Map(body) = &mut body {
+            body.insert(Value::Text("ops".into()), Value::Array(vec![op]));
+            body.insert(Value::Text("blocks".into()), Value::Bytes(blocks));
+        }
+        frame("#commit", body)
+    }
+
+    /// Columns whose value depends on when ingestion ran rather than on what
+    /// it read
+    fn is_clock_column(table: &str, column: &str) -> bool {
+        matches!(column, "indexed_at" | "updated_at" | "last_enriched" | "last_at")
+            || (column == "created_at" && table != "posts")
+    }
+
+    /// Every row ingestion left in the database, apart from the cursor
+    fn dump(path: &str) -> Vec<String> {
+        let conn = sqlite::open(path).unwrap();
+        let mut tables = Vec::new();
+        conn.iterate("SELECT name FROM sqlite_master WHERE type = 'table' AND name != 'metadata' ORDER BY name", |row| {
+            tables.extend(row[0].1.map(String::from));
+            true
+        }).unwrap();
+
+        let mut rows = Vec::new();
+        for table in tables {
+            let mut statement = conn.prepare(format!("SELECT * FROM {} ORDER BY 1, 2, 3", table)).unwrap();
+            let names = statement.column_names().to_vec();
+            while let Ok(sqlite::State::Row) = statement.next() {
+                let columns: Vec<String> = names.iter()
+                    .enumerate()
+                    .filter(|(_, name)| !is_clock_column(&table, name))
+                    .map(|(i, name)| format!("{}={:?}", name, statement.read::<sqlite::Value, _>(i).unwrap()))
+                    .collect();
+                rows.push(format!("{}: {}", table, columns.join(", ")));
+            }
+        }
+        rows
+    }
+
+    #[test]
+    fn matches_firehose_writes() {
+        let (url, server) = replay_server();
+        let jetstream_db = temp_db("jetstream");
+        let mut filter = Filter::new(Database::new(&jetstream_db));
+        let mut cursor = CursorTracker::new(CURSOR_KEY);
+        stream(&mut filter, &mut cursor, &subscribe_url(&url, None));
+        cursor.flush(&mut filter);
+
+        let requested = server.join().unwrap();
+        for collection in WANTED_COLLECTIONS {
+            assert!(requested.contains(&format!("wantedCollections={}", collection)), "{}", requested);
+        }
+        // The cursor is the last event's time_us
+        assert_eq!(filter.db.get_metadata(CURSOR_KEY).unwrap().seq, 1760000000000011);
+
+        let firehose_db = temp_db("firehose");
+        let mut frames = RECORDED.lines()
+            .enumerate()
+            .map(|(i, line)| firehose_frame(i as i64 + 1, line));
+        let mut firehose = Filter::new(Database::new(&firehose_db));
+        pipeline::run(&mut firehose, &Verifier::from_env(), || frames.next(), |_, _| true);
+        firehose.flush(None);
+
+        let written = dump(&jetstream_db);
+        assert_eq!(written, dump(&firehose_db));
+
+        // One Toronto post left: the other was deleted by its author, and the
+        // third author's account was taken down. One like is left after the
+        // unlike, and the author's new handle is recorded.
+        let posts: Vec<&String> = written.iter().filter(|row| row.starts_with("posts:")).collect();
+        assert_eq!(posts.len(), 1, "{:#?}", written);
+        assert!(posts[0].contains(&format!("uri=String(\"{}\")", POST_URI))
+            && posts[0].contains("likes=Integer(1), reposts=Integer(1)"), "{:#?}", written);
+        assert!(written.contains(&"handles: did=String(\"did:plc:w4xbfzo7kqfes5zb7r6qv3rw\"), handle=String(\"streetcarwatcher.bsky.social\")".to_string()),
+            "{:#?}", written);
+
+        for path in [jetstream_db, firehose_db] {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
@@ -0,0 +1,18 @@
+// Everything but main lives in the library so benches/ and fuzz/ can reach it
+pub mod models;
+pub mod db;
+pub mod ingestion;
+pub mod parser;
+pub mod filter;
+pub mod server;
+pub mod enrichment;
+pub mod backfill;
+pub mod jetstream;
+pub mod capture;
+pub mod relay;
+pub mod verify;
+mod did;
+pub mod pipeline;
+pub mod rules;
+pub mod matcher;
+pub mod gazetteer;
@@ -1,28 +1,89 @@
-mod models;
-mod db;
-mod ingestion;
-mod parser;
-mod filter;
-mod server;
-mod enrichment;
-mod backfill;
+use std::io::Write;
 use std::thread;
 
-use crate::db::Database;
-use crate::enrichment::EnrichThread;
-use crate::filter::Filter;
+use to_sky::{backfill, ingestion, jetstream, rules, server};
+use to_sky::db::Database;
+use to_sky::enrichment::EnrichThread;
+use to_sky::filter::Filter;
 
 fn db_path() -> String {
     std::env::var("TOSKY_DB_PATH").unwrap_or_else(|_| "../db/posts.db".to_string())
 }
 
+/// "firehose" (default) decodes subscribeRepos CAR blocks, "jetstream" reads
+/// Jetstream's filtered JSON stream instead, and "replay" feeds a capture file
+/// recorded with TOSKY_CAPTURE_PATH through the firehose pipeline
+fn ingestion_source() -> String {
+    std::env::var("TOSKY_INGESTION").unwrap_or_else(|_| "firehose".to_string())
+}
+
+fn jetstream_url() -> String {
+    std::env::var("TOSKY_JETSTREAM_URL")
+        .unwrap_or_else(|_| "wss://jetstream2.us-east.bsky.network/subscribe".to_string())
+}
+
+/// `to-sky why <post>` prints why a post is in the feed. The post can be an
+/// at:// URI or a bsky.app link.
+fn explain(db: &Database, link: &str, out: &mut impl Write) -> i32 {
+    let uri = db.post_uri(link).unwrap_or_else(|| link.to_string());
+
+    match db.get_match_reason(&uri) {
+        Some(reason) if reason.field.is_empty() => {
+            writeln!(out, "{} is in the feed, but was added before match reasons were recorded", uri).ok();
+            0
+        },
+        Some(reason) => {
+            writeln!(out, "{}", uri).ok();
+            if !reason.rules.is_empty() {
+                let rules: Vec<String> = reason.rules.iter().map(|rule| format!("{:?}", rule)).collect();
+                writeln!(out, "  matched: {}", rules.join(", ")).ok();
+            }
+            writeln!(out, "  field:   {}", reason.field).ok();
+            writeln!(out, "  snippet: {}", reason.snippet).ok();
+            0
+        },
+        None => {
+            writeln!(out, "{} is not in the feed", uri).ok();
+            1
+        },
+    }
+}
+
 fn main() {
     let db_path = db_path();
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("why") {
+        match args.get(2) {
+            Some(link) => std::process::exit(explain(&Database::new(&db_path), link, &mut std::io::stdout())),
+            None => {
+                eprintln!("usage: to-sky why <at:// uri or bsky.app link>");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    rules::init_from_env();
+
     {
         let _ = Database::new(&db_path);
     }
 
+    // Offline replay only runs the ingestion pipeline: no backfill, enrichment
+    // or server, so it's reproducible and doesn't touch the network
+    if ingestion_source() == "replay" {
+        let capture_path = std::env::var("TOSKY_REPLAY_PATH")
+            .unwrap_or_else(|_| "capture.bin".to_string());
+        let speed = std::env::var("TOSKY_REPLAY_SPEED")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0.0);
+
+        let mut filter = Filter::new(Database::new(&db_path));
+        ingestion::start_replay(&mut filter, &capture_path, speed);
+        return;
+    }
+
     let enrichment_db_path = db_path.clone();
     let enrichment_handle = thread::spawn(move || {
         let mut enrich = EnrichThread::new(&enrichment_db_path);
@@ -54,10 +115,56 @@ fn main() {
         let db = Database::new(&ingestion_db_path);
         let mut filter: Filter = Filter::new(db);
 
-        ingestion::start_ingestion(&mut filter);
+        match ingestion_source().as_str() {
+            "jetstream" => jetstream::start_jetstream(&mut filter, &jetstream_url()),
+            _ => ingestion::start_ingestion(&mut filter),
+        }
     });
 
     enrichment_handle.join().unwrap();
     ingestion_handle.join().unwrap();
     server_handle.join().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use to_sky::db::Write as DbWrite;
+    use to_sky::models::{MatchReason, TorontoPost};
+
+    fn why(db: &Database, link: &str) -> (i32, String) {
+        let mut out = Vec::new();
+        let code = explain(db, link, &mut out);
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn why_reports_the_match_reason() {
+        let mut db = Database::new(":memory:");
+        let uri = "at://did:plc:a/app.bsky.feed.post/3m2qz";
+        let post = TorontoPost {
+            uri: uri.to_string(),
+            cid: "bafyreib2rxk3rh6kzwq".to_string(),
+            did: "did:plc:a".to_string(),
+            indexed_at: 1_700_000_000,
+            created_at: 1_700_000_000,
+            reply_parent: None,
+            reply_root: None,
+            reason: MatchReason {
+                rules: vec!["toronto".to_string(), "ttc".to_string()],
+                field: "text".to_string(),
+                snippet: "...the TTC in Toronto...".to_string(),
+            },
+        };
+        db.apply_writes(&[DbWrite::InsertPost(post)], None).unwrap();
+        db.set_handle("did:plc:a", Some("alice.bsky.social"));
+
+        let expected = format!("{}\n  matched: \"toronto\", \"ttc\"\n  field:   text\n  snippet: ...the TTC in Toronto...\n", uri);
+        for link in [uri, "https://bsky.app/profile/alice.bsky.social/post/3m2qz"] {
+            assert_eq!(why(&db, link), (0, expected.clone()), "{:?}", link);
+        }
+
+        let missing = "at://did:plc:a/app.bsky.feed.post/other";
+        assert_eq!(why(&db, missing), (1, format!("{} is not in the feed\n", missing)));
+    }
+}
@@ -0,0 +1,973 @@
+use crate::filter::MatchSource;
+use aho_corasick::AhoCorasick;
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::sync::Arc;
+
+// Rule expressions, compiled once when the rule file loads.
+//
+//   toronto                 whole word, any case
+//   "cn tower"              phrase: these words in a row
+//   toronto*  *onto         word prefix / suffix
+//   *toronto*               substring, like the old lax keywords
+//   /yyz|pearson/           regex over the lowercased field
+//   a AND b, a b            both (AND is implied between terms)
+//   a OR b, NOT a, ( ... )  as usual; NOT binds tightest, then AND, then OR
+//   a NEAR/5 b              words or phrases within 5 words, in the same field
+//   alt:yyz, tag:(a OR b)   only look in some fields
+//
+// Fields: text, tag, link, alt, title, description.
+//
+// Nearly every post matches nothing, so before any of that runs a single
+// Aho-Corasick automaton scans the original text of each field for the
+// literal text the rules need (see Expr::needles). Only posts it finds
+// something in are lowercased and split into words, and only the rules
+// whose text turned up are evaluated.
+
+/// Bytes of context kept either side of a match in its snippet
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Words in so many place names that a phrase's needle is one of its other
+/// words if it has any, e.g. "dundas" for "Dundas Street West"
+const COMMON_WORDS: &[&str] = &[
+    "the", "and", "of", "st", "street", "avenue", "ave", "road", "park", "station", "village", "north",
+    "south", "east", "west", "centre", "center", "square", "hall", "place", "beach", "island", "heights",
+    "mall", "theatre", "museum", "gardens", "hill", "city", "new", "old", "mount", "point", "market",
+];
+
+const FIELDS: &[(&str, &[MatchSource])] = &[
+    ("text", &[MatchSource::Text]),
+    ("tag", &[MatchSource::Hashtag, MatchSource::Tag]),
+    ("link", &[MatchSource::Link, MatchSource::CardUrl]),
+    ("alt", &[MatchSource::ImageAlt, MatchSource::VideoAlt]),
+    ("title", &[MatchSource::CardTitle]),
+    ("description", &[MatchSource::CardDescription]),
+];
+
+/// A set of MatchSources a term is allowed to match in
+#[derive(Clone, Copy)]
+struct Scope(u16);
+
+impl Scope {
+    const ALL: Scope = Scope(u16::MAX);
+
+    fn of(sources: &[MatchSource]) -> Scope {
+        Scope(sources.iter().fold(0, |bits, &source| bits | 1 << source as u16))
+    }
+
+    fn contains(self, source: MatchSource) -> bool {
+        self.0 & (1 << source as u16) != 0
+    }
+}
+
+enum Term {
+    Word(String),
+    Prefix(String),
+    Suffix(String),
+    Substring(String),
+    Phrase(Vec<String>),
+    Regex(Regex),
+}
+
+enum Expr {
+    Term(Term, Scope),
+    Near(Term, Term, usize, Scope),
+    /// A word or phrase with context anywhere in the post, or within so many
+    /// words of it. Only built by Matcher::add_words_in_context.
+    InContext(Term, Arc<Context>, Option<usize>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+}
+
+/// One field of a post, lowercased and split into words once so every rule
+/// can share the work
+pub struct Field<'a> {
+    source: MatchSource,
+    text: &'a str,
+    words: Vec<&'a str>,
+}
+
+/// Splits fields into words. Expects the lowercased fields from match_fields.
+pub fn tokenize(fields: &[(MatchSource, String)]) -> Vec<Field<'_>> {
+    fields.iter()
+        .map(|(source, text)| Field { source: *source, text, words: words(text).collect() })
+        .collect()
+}
+
+/// Words are runs of letters and digits, so "#6ix's" is "6ix" and "s"
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty())
+}
+
+impl Field<'_> {
+    /// Byte range of words[first..=last] in the text
+    fn span(&self, first: usize, last: usize) -> Range<usize> {
+        let offset = |word: &str| word.as_ptr() as usize - self.text.as_ptr() as usize;
+        offset(self.words[first])..offset(self.words[last]) + self.words[last].len()
+    }
+}
+
+/// Where in a post an expression matched: an index into the fields and a
+/// byte range in that field's text
+struct Hit {
+    field: usize,
+    span: Range<usize>,
+}
+
+impl Term {
+    /// Byte range of the first match in the field
+    fn find(&self, field: &Field) -> Option<Range<usize>> {
+        match self {
+            Term::Substring(s) => field.text.find(s.as_str()).map(|i| i..i + s.len()),
+            Term::Regex(re) => re.find(field.text).map(|m| m.range()),
+            _ => self.positions(field).next().map(|i| field.span(i, i + self.len() - 1)),
+        }
+    }
+
+    /// Literal text any match contains. The prefilter only folds ASCII case,
+    /// so for words with other cased letters it's the longest ASCII run.
+    fn needle(&self) -> Option<Needle> {
+        let (text, word_start, word_end) = match self {
+            Term::Word(s) => (s.as_str(), true, true),
+            Term::Prefix(s) => (s.as_str(), true, false),
+            Term::Suffix(s) => (s.as_str(), false, true),
+            Term::Substring(s) => (s.as_str(), false, false),
+            Term::Phrase(words) => {
+                let uncommon = words.iter().filter(|word| !COMMON_WORDS.contains(&word.as_str()));
+                let needle = uncommon.max_by_key(|word| word.len()).or(words.iter().max_by_key(|word| word.len()))?;
+                (needle.as_str(), true, true)
+            },
+            Term::Regex(_) => return None,
+        };
+
+        if text.is_ascii() || text.to_uppercase() == text {
+            return Some(Needle { text: text.to_string(), word_start, word_end });
+        }
+        text.split(|c: char| !c.is_ascii())
+            .max_by_key(|run| run.len())
+            .filter(|run| !run.is_empty())
+            .map(|run| Needle { text: run.to_string(), word_start: false, word_end: false })
+    }
+
+    /// How many words a match covers
+    fn len(&self) -> usize {
+        match self {
+            Term::Phrase(phrase) => phrase.len(),
+            _ => 1,
+        }
+    }
+
+    /// Word offsets where the term starts, for words and phrases
+    fn positions<'a>(&'a self, field: &'a Field) -> impl Iterator<Item = usize> + 'a {
+        (0..field.words.len()).filter(move |&i| self.starts_at(&field.words, i))
+    }
+
+    fn starts_at(&self, words: &[&str], i: usize) -> bool {
+        match self {
+            Term::Word(w) => words[i] == w,
+            Term::Prefix(p) => words[i].starts_with(p.as_str()),
+            Term::Suffix(s) => words[i].ends_with(s.as_str()),
+            Term::Phrase(phrase) => words.len() - i >= phrase.len()
+                && phrase.iter().zip(&words[i..]).all(|(p, w)| p == w),
+            Term::Substring(_) | Term::Regex(_) => false,
+        }
+    }
+}
+
+impl Expr {
+    /// None if the expression doesn't match. Otherwise what made it match, if
+    /// there is something (NOT matches without pointing at anything).
+    fn eval(&self, fields: &[Field]) -> Option<Option<Hit>> {
+        let in_scope = |scope: Scope| fields.iter().enumerate().filter(move |(_, f)| scope.contains(f.source));
+
+        match self {
+            Expr::Term(term, scope) => in_scope(*scope)
+                .find_map(|(i, f)| term.find(f).map(|span| Hit { field: i, span }))
+                .map(Some),
+            Expr::Near(a, b, distance, scope) => in_scope(*scope)
+                .find_map(|(i, f)| {
+                    let (first, last) = a.positions(f).find_map(|x| {
+                        b.positions(f).find(|y| x.abs_diff(*y) <= *distance).map(|y| {
+                            (x.min(y), (x + a.len()).max(y + b.len()) - 1)
+                        })
+                    })?;
+                    Some(Hit { field: i, span: f.span(first, last) })
+                })
+                .map(Some),
+            Expr::InContext(term, context, near) => {
+                let hit = fields.iter().enumerate().find_map(|(i, f)| {
+                    let x = term.positions(f).find(|&x| near.is_none_or(|distance| context.near(f, x, distance)))?;
+                    Some(Hit { field: i, span: f.span(x, x + term.len() - 1) })
+                })?;
+                if near.is_none() && !fields.iter().any(|f| context.anywhere(f)) {
+                    return None;
+                }
+                Some(Some(hit))
+            },
+            Expr::And(exprs) => {
+                let mut hit = None;
+                for expr in exprs {
+                    let next = expr.eval(fields)?;
+                    hit = hit.or(next);
+                }
+                Some(hit)
+            },
+            Expr::Or(exprs) => exprs.iter().find_map(|expr| expr.eval(fields)),
+            Expr::Not(expr) => match expr.eval(fields) {
+                Some(_) => None,
+                None => Some(None),
+            },
+        }
+    }
+
+    /// Needles the expression can't match without: if none of them appear in
+    /// any field, it doesn't match. None if there's no such text, e.g. for a
+    /// bare regex.
+    fn needles(&self) -> Option<Vec<Needle>> {
+        match self {
+            Expr::Term(term, _) | Expr::Near(term, _, _, _) | Expr::InContext(term, _, _) => {
+                term.needle().map(|needle| vec![needle])
+            },
+            Expr::And(exprs) => exprs.iter().filter_map(Expr::needles).min_by_key(Vec::len),
+            Expr::Or(exprs) => exprs.iter().map(Expr::needles).collect::<Option<Vec<_>>>().map(|sets| sets.concat()),
+            Expr::Not(_) => None,
+        }
+    }
+
+    /// Whether the expression can only match by finding something. Rules like
+    /// "NOT spam" would match nearly every post, so they're rejected.
+    fn is_positive(&self) -> bool {
+        match self {
+            Expr::Term(..) | Expr::Near(..) | Expr::InContext(..) => true,
+            Expr::And(exprs) => exprs.iter().any(Expr::is_positive),
+            Expr::Or(exprs) => exprs.iter().all(Expr::is_positive),
+            Expr::Not(_) => false,
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    expr: Expr,
+}
+
+/// A rule that matched a post, where, and the text around the match
+pub struct Match<'a> {
+    pub rule: &'a str,
+    pub source: MatchSource,
+    pub snippet: String,
+}
+
+/// Words and phrases that show an ambiguous keyword is about the place we
+/// mean, see Matcher::add_words_in_context. Indexed so a post can be checked
+/// against hundreds of them in one pass over its words.
+pub struct Context {
+    /// Words and phrases, by their first word
+    by_first_word: HashMap<String, Vec<Term>>,
+    /// Prefixes and suffixes, which have to be tried at every word
+    partial: Vec<Term>,
+}
+
+impl Context {
+    pub fn new<'a>(keywords: impl IntoIterator<Item = &'a str>) -> Result<Context, String> {
+        let mut context = Context { by_first_word: HashMap::new(), partial: Vec::new() };
+        for keyword in keywords {
+            match word_term(keyword).map_err(|e| format!("context {:?}: {}", keyword, e))? {
+                Term::Word(word) => context.by_first_word.entry(word.clone()).or_default().push(Term::Word(word)),
+                Term::Phrase(words) => context.by_first_word.entry(words[0].clone()).or_default().push(Term::Phrase(words)),
+                term @ (Term::Prefix(_) | Term::Suffix(_)) => context.partial.push(term),
+                _ => return Err(format!("context {:?}: only words and phrases work as context", keyword)),
+            }
+        }
+        Ok(context)
+    }
+
+    fn anywhere(&self, field: &Field) -> bool {
+        self.near(field, 0, field.words.len())
+    }
+
+    /// Whether something from the context starts within distance words of
+    /// word x in the field
+    fn near(&self, field: &Field, x: usize, distance: usize) -> bool {
+        let words = &field.words;
+        let last = (x + distance).min(words.len().saturating_sub(1));
+        (x.saturating_sub(distance)..=last).filter(|&y| y < words.len()).any(|y| {
+            self.by_first_word.get(words[y]).is_some_and(|terms| terms.iter().any(|term| term.starts_at(words, y)))
+                || self.partial.iter().any(|term| term.starts_at(words, y))
+        })
+    }
+}
+
+/// Literal text the prefilter looks for, and whether it has to start or end
+/// a word to count
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Needle {
+    text: String,
+    word_start: bool,
+    word_end: bool,
+}
+
+/// Every keyword and rule from the rule file, ready to run against a post
+#[derive(Default)]
+pub struct Matcher {
+    rules: Vec<Rule>,
+    /// Built by finish. None makes every rule a candidate for every post.
+    prefilter: Option<Prefilter>,
+}
+
+/// One automaton over every rule's needles
+struct Prefilter {
+    automaton: AhoCorasick,
+    /// In pattern order
+    needles: Vec<Needle>,
+    /// For each needle, the rules that need it
+    needle_rules: Vec<Vec<usize>>,
+}
+
+/// The rules a post could match, filled in by Matcher::scan. Allocates
+/// nothing until the first needle turns up.
+#[derive(Default)]
+pub struct Candidates {
+    all: bool,
+    rules: Vec<bool>,
+}
+
+impl Candidates {
+    /// Every rule, for checking a post without the prefilter
+    pub fn all() -> Self {
+        Candidates { all: true, rules: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.rules.is_empty()
+    }
+
+    fn contains(&self, rule: usize) -> bool {
+        self.all || self.rules.get(rule).copied().unwrap_or(false)
+    }
+}
+
+impl Matcher {
+    /// Matched anywhere in any field, the old lax keyword behaviour
+    pub fn add_substring(&mut self, keyword: &str) {
+        let expr = Expr::Term(Term::Substring(keyword.to_lowercase()), Scope::ALL);
+        self.rules.push(Rule { name: keyword.to_string(), expr });
+    }
+
+    /// Matched as a whole word or phrase in any field
+    pub fn add_words(&mut self, keyword: &str) -> Result<(), String> {
+        let term = word_term(keyword).map_err(|e| format!("keyword {:?}: {}", keyword, e))?;
+        self.rules.push(Rule { name: keyword.to_string(), expr: Expr::Term(term, Scope::ALL) });
+        Ok(())
+    }
+
+    /// Matched as a whole word or phrase, but only with something from the
+    /// context somewhere in the post, or within near words of it in the same
+    /// field if near is given
+    pub fn add_words_in_context(&mut self, keyword: &str, context: &Arc<Context>, near: Option<usize>) -> Result<(), String> {
+        let term = match word_term(keyword) {
+            Ok(Term::Substring(_)) => Err("only words and phrases can need context".to_string()),
+            term => term,
+        }.map_err(|e| format!("keyword {:?}: {}", keyword, e))?;
+        let expr = Expr::InContext(term, context.clone(), near);
+        self.rules.push(Rule { name: keyword.to_string(), expr });
+        Ok(())
+    }
+
+    pub fn add_rule(&mut self, name: &str, source: &str) -> Result<(), String> {
+        let expr = Parser::new(source).and_then(|mut parser| parser.parse())?;
+
+        if !expr.is_positive() {
+            return Err("it only says what not to match, so it would match nearly everything".to_string());
+        }
+
+        self.rules.push(Rule { name: name.to_string(), expr });
+        Ok(())
+    }
+
+    /// Builds the prefilter once every keyword and rule has been added. If a
+    /// rule has no literal text to look for, there's no prefilter and the
+    /// name of that rule is returned.
+    pub fn finish(&mut self) -> Option<&str> {
+        let mut needles: Vec<Needle> = Vec::new();
+        let mut needle_ids: HashMap<Needle, usize> = HashMap::new();
+        let mut needle_rules: Vec<Vec<usize>> = Vec::new();
+        for (i, rule) in self.rules.iter().enumerate() {
+            let Some(rule_needles) = rule.expr.needles() else {
+                self.prefilter = None;
+                return Some(&rule.name);
+            };
+
+            for mut needle in rule_needles {
+                // Case folded the same way the automaton does
+                needle.text.make_ascii_lowercase();
+                match needle_ids.get(&needle) {
+                    Some(&n) => needle_rules[n].push(i),
+                    None => {
+                        needle_ids.insert(needle.clone(), needles.len());
+                        needles.push(needle);
+                        needle_rules.push(vec![i]);
+                    },
+                }
+            }
+        }
+
+        for rules in &mut needle_rules {
+            rules.dedup();
+        }
+        self.prefilter = AhoCorasick::builder()
+            .ascii_case_insensitive(true)
+            .build(needles.iter().map(|needle| &needle.text))
+            .ok()
+            .map(|automaton| Prefilter { automaton, needles, needle_rules });
+        None
+    }
+
+    /// Adds the rules that could match because of this field to candidates,
+    /// from the field as it is without lowercasing or copying it
+    pub fn scan(&self, text: &str, candidates: &mut Candidates) {
+        let Some(prefilter) = &self.prefilter else {
+            candidates.all = true;
+            return;
+        };
+        if candidates.all {
+            return;
+        }
+
+        for found in prefilter.automaton.find_overlapping_iter(text) {
+            // Words are runs of letters and digits, as in tokenize
+            let needle = &prefilter.needles[found.pattern().as_usize()];
+            let joined_before = text[..found.start()].chars().next_back().is_some_and(char::is_alphanumeric);
+            let joined_after = text[found.end()..].chars().next().is_some_and(char::is_alphanumeric);
+            if (needle.word_start && joined_before) || (needle.word_end && joined_after) {
+                continue;
+            }
+
+            if candidates.rules.is_empty() {
+                candidates.rules.resize(self.rules.len(), false);
+            }
+            for &rule in &prefilter.needle_rules[found.pattern().as_usize()] {
+                candidates.rules[rule] = true;
+            }
+        }
+    }
+
+    /// Every candidate rule that matches, in order. Lazy, so taking just the
+    /// first stops as soon as one matches.
+    pub fn matches<'a: 'f, 'f>(&'a self, fields: &'f [Field<'f>], candidates: &'f Candidates) -> impl Iterator<Item = Match<'a>> + 'f {
+        self.rules.iter().enumerate()
+            .filter(|(i, _)| candidates.contains(*i))
+            .filter_map(move |(_, rule)| {
+                // Rules always have something positive to point at, add_rule makes sure
+                let hit = rule.expr.eval(fields)?.unwrap_or(Hit { field: 0, span: 0..0 });
+                let field = &fields[hit.field];
+                Some(Match { rule: &rule.name, source: field.source, snippet: snippet(field.text, hit.span) })
+            })
+    }
+}
+
+/// The match plus some context either side, on one line
+fn snippet(text: &str, span: Range<usize>) -> String {
+    let mut start = span.start.saturating_sub(SNIPPET_CONTEXT);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (span.end + SNIPPET_CONTEXT).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+
+    let context = text[start..end].split_whitespace().collect::<Vec<_>>().join(" ");
+    let before = if start > 0 { "…" } else { "" };
+    let after = if end < text.len() { "…" } else { "" };
+    format!("{}{}{}", before, context, after)
+}
+
+/// A bare word or the inside of a quoted phrase. Anything with punctuation
+/// in it is split the same way post text is, so "yonge-dundas" and
+/// "blogto.com" become phrases.
+fn word_term(raw: &str) -> Result<Term, String> {
+    let lower = raw.to_lowercase();
+
+    let (prefix_wild, rest) = match lower.strip_prefix('*') {
+        Some(rest) => (true, rest),
+        None => (false, lower.as_str()),
+    };
+    let (suffix_wild, core) = match rest.strip_suffix('*') {
+        Some(core) => (true, core),
+        None => (false, rest),
+    };
+
+    if prefix_wild && suffix_wild {
+        if core.is_empty() {
+            return Err("wildcard without any text".to_string());
+        }
+        return Ok(Term::Substring(core.to_string()));
+    }
+
+    let parts: Vec<String> = words(core).map(String::from).collect();
+    match (parts.as_slice(), prefix_wild, suffix_wild) {
+        ([], _, _) => Err(format!("{:?} has no letters or digits to match", raw)),
+        ([word], false, false) => Ok(Term::Word(word.clone())),
+        ([word], false, true) => Ok(Term::Prefix(word.clone())),
+        ([word], true, false) => Ok(Term::Suffix(word.clone())),
+        (_, false, false) => Ok(Term::Phrase(parts)),
+        _ => Err(format!("{:?}: wildcards only work on a single word", raw)),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Near(usize),
+    Field(&'static str),
+    Word(String),
+    Phrase(String),
+    Regex(String),
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<(usize, Token)>>,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self, String> {
+        Ok(Parser { tokens: lex(source)?.into_iter().peekable() })
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.tokens.peek().map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        self.tokens.next()
+    }
+
+    fn parse(&mut self) -> Result<Expr, String> {
+        if self.peek().is_none() {
+            return Err("empty expression".to_string());
+        }
+
+        let expr = self.or(Scope::ALL)?;
+        match self.next() {
+            None => Ok(expr),
+            Some((col, token)) => Err(format!("unexpected {} at column {}", describe(&token), col)),
+        }
+    }
+
+    fn or(&mut self, scope: Scope) -> Result<Expr, String> {
+        let mut exprs = vec![self.and(scope)?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            exprs.push(self.and(scope)?);
+        }
+        Ok(if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::Or(exprs) })
+    }
+
+    fn and(&mut self, scope: Scope) -> Result<Expr, String> {
+        let mut exprs = vec![self.unary(scope)?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                },
+                // Terms next to each other are ANDed
+                Some(Token::Or | Token::RParen) | None => break,
+                Some(_) => {},
+            }
+            exprs.push(self.unary(scope)?);
+        }
+        Ok(if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::And(exprs) })
+    }
+
+    fn unary(&mut self, scope: Scope) -> Result<Expr, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.unary(scope)?)));
+        }
+
+        let expr = self.primary(scope)?;
+        if let Some(&Token::Near(distance)) = self.peek() {
+            let (col, _) = self.next().unwrap();
+            let right = self.primary(scope)?;
+            return match (expr, right) {
+                // Scoped by the left side, so alt:yyz NEAR/3 airport only looks in alt text
+                (Expr::Term(a, scope), Expr::Term(b, _)) if is_positional(&a) && is_positional(&b) => {
+                    Ok(Expr::Near(a, b, distance, scope))
+                },
+                _ => Err(format!("NEAR at column {} needs a word or phrase on each side", col)),
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn primary(&mut self, scope: Scope) -> Result<Expr, String> {
+        match self.next() {
+            Some((_, Token::LParen)) => {
+                let expr = self.or(scope)?;
+                match self.next() {
+                    Some((_, Token::RParen)) => Ok(expr),
+                    Some((col, token)) => Err(format!("expected ')' at column {}, found {}", col, describe(&token))),
+                    None => Err("missing ')'".to_string()),
+                }
+            },
+            Some((_, Token::Field(name))) => {
+                let sources = FIELDS.iter().find(|(field, _)| *field == name).unwrap().1;
+                self.primary(Scope::of(sources))
+            },
+            Some((col, Token::Word(word))) => word_term(&word)
+                .map(|term| Expr::Term(term, scope))
+                .map_err(|e| format!("{} at column {}", e, col)),
+            Some((col, Token::Phrase(phrase))) => match word_term(&phrase) {
+                Ok(term @ (Term::Word(_) | Term::Phrase(_))) => Ok(Expr::Term(term, scope)),
+                Ok(_) => Err(format!("wildcards don't work inside quotes (column {})", col)),
+                Err(e) => Err(format!("{} at column {}", e, col)),
+            },
+            Some((col, Token::Regex(pattern))) => RegexBuilder::new(&pattern)
+                .case_insensitive(true)
+                .build()
+                .map(|re| Expr::Term(Term::Regex(re), scope))
+                .map_err(|e| format!("bad regex at column {}: {}", col, e)),
+            Some((col, token)) => Err(format!("expected a term at column {}, found {}", col, describe(&token))),
+            None => Err("expression ends early".to_string()),
+        }
+    }
+}
+
+fn is_positional(term: &Term) -> bool {
+    !matches!(term, Term::Substring(_) | Term::Regex(_))
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::LParen => "'('".to_string(),
+        Token::RParen => "')'".to_string(),
+        Token::And => "AND".to_string(),
+        Token::Or => "OR".to_string(),
+        Token::Not => "NOT".to_string(),
+        Token::Near(n) => format!("NEAR/{}", n),
+        Token::Field(name) => format!("{}:", name),
+        Token::Word(w) => format!("{:?}", w),
+        Token::Phrase(p) => format!("\"{}\"", p),
+        Token::Regex(r) => format!("/{}/", r),
+    }
+}
+
+/// Splits an expression into tokens, each with its 1-based column
+fn lex(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let col = i + 1;
+        match chars[i] {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push((col, Token::LParen));
+                i += 1;
+            },
+            ')' => {
+                tokens.push((col, Token::RParen));
+                i += 1;
+            },
+            '"' => {
+                let end = chars[i + 1..].iter().position(|&c| c == '"')
+                    .ok_or_else(|| format!("unclosed quote at column {}", col))?;
+                tokens.push((col, Token::Phrase(chars[i + 1..i + 1 + end].iter().collect())));
+                i += end + 2;
+            },
+            '/' => {
+                // Runs to the next unescaped '/', "\/" is a literal slash
+                let mut pattern = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        None => return Err(format!("unclosed regex at column {}", col)),
+                        Some('/') => break,
+                        Some('\\') if chars.get(j + 1) == Some(&'/') => {
+                            pattern.push('/');
+                            j += 2;
+                        },
+                        Some(&c) => {
+                            pattern.push(c);
+                            j += 1;
+                        },
+                    }
+                }
+                tokens.push((col, Token::Regex(pattern)));
+                i = j + 1;
+            },
+            _ => {
+                let end = chars[i..].iter()
+                    .position(|&c| c.is_whitespace() || matches!(c, '(' | ')' | '"'))
+                    .map_or(chars.len(), |len| i + len);
+                let word: String = chars[i..end].iter().collect();
+
+                // Only a known field name makes a prefix, so 10:30 and
+                // https://... are still words
+                let field = word.split_once(':')
+                    .and_then(|(name, rest)| Some((FIELDS.iter().find(|(field, _)| *field == name)?.0, rest)));
+                if let Some((name, rest)) = field {
+                    tokens.push((col, Token::Field(name)));
+                    // The term right after the colon, e.g. alt:yyz. Quotes,
+                    // regexes and groups are lexed on the next pass.
+                    i += name.chars().count() + 1;
+                    if !rest.is_empty() && !rest.starts_with('/') {
+                        tokens.push((i + 1, Token::Word(rest.to_string())));
+                        i = end;
+                    }
+                    continue;
+                }
+
+                let token = match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => match word.strip_prefix("NEAR/") {
+                        Some(n) => Token::Near(n.parse().map_err(|_| format!("bad NEAR distance {:?} at column {}", n, col))?),
+                        None => Token::Word(word),
+                    },
+                };
+                tokens.push((col, token));
+                i = end;
+            },
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether rule matches the fields, checking that going through the
+    /// prefilter like match_post gives the same answer as evaluating it
+    /// against the whole post
+    fn matches(rule: &str, fields: &[(MatchSource, &str)]) -> bool {
+        let mut matcher = Matcher::default();
+        matcher.add_rule("rule", rule).unwrap();
+        let lowered: Vec<_> = fields.iter().map(|(source, text)| (*source, text.to_lowercase())).collect();
+        let tokenized = tokenize(&lowered);
+        let full = matcher.matches(&tokenized, &Candidates::all()).next().is_some();
+
+        if matcher.finish().is_none() {
+            let mut candidates = Candidates::default();
+            for (_, text) in fields {
+                matcher.scan(text, &mut candidates);
+            }
+            let prefiltered = matcher.matches(&tokenized, &candidates).next().is_some();
+            assert_eq!(prefiltered, full, "prefilter disagrees on {:?} over {:?}", rule, fields);
+        }
+        full
+    }
+
+    fn text(text: &str) -> Vec<(MatchSource, &str)> {
+        vec![(MatchSource::Text, text)]
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (source, error) in [
+            ("", "empty expression"),
+            ("   ", "empty expression"),
+            ("(a", "missing ')'"),
+            ("a )", "unexpected ')' at column 3"),
+            ("(a b c", "missing ')'"),
+            ("(a b\"c\"", "missing ')'"),
+            ("a AND", "expression ends early"),
+            ("a AND OR b", "expected a term at column 7, found OR"),
+            ("\"cn tower", "unclosed quote at column 1"),
+            ("a /yyz", "unclosed regex at column 3"),
+            ("/(/", "bad regex at column 1"),
+            ("a NEAR/x b", "bad NEAR distance \"x\" at column 3"),
+            ("*onto* NEAR/3 b", "NEAR at column 8 needs a word or phrase on each side"),
+            ("a NEAR/3 /b/", "NEAR at column 3 needs a word or phrase on each side"),
+            ("tag:\"to*\"", "wildcards don't work inside quotes (column 5)"),
+            ("alt:*", "\"*\" has no letters or digits to match at column 5"),
+            ("to*ron*", "\"to*ron*\": wildcards only work on a single word at column 1"),
+            ("NOT spam", "it only says what not to match"),
+            ("toronto OR NOT spam", "it only says what not to match"),
+        ] {
+            let error_for = Matcher::default().add_rule("rule", source).err();
+            assert!(
+                error_for.as_deref().is_some_and(|e| e.starts_with(error)),
+                "{:?} gave {:?}, expected {:?}", source, error_for, error,
+            );
+        }
+    }
+
+    #[test]
+    fn parses() {
+        for source in [
+            "toronto",
+            "toronto*",
+            "*onto",
+            "*ront*",
+            "\"cn tower\"",
+            "/yyz|pearson/",
+            "/a\\/b/",
+            "a AND b OR c",
+            "NOT (a OR b) c",
+            "a NEAR/5 \"b c\"",
+            "alt:yyz",
+            "tag:(a OR b)",
+            "alt:/pearson/",
+            "10:30",
+            "https://blogto.com",
+            "foo:bar",
+        ] {
+            let result = Matcher::default().add_rule("rule", source);
+            assert!(result.is_ok(), "{:?} failed with {:?}", source, result);
+        }
+    }
+
+    #[test]
+    fn terms() {
+        for (rule, post, expected) in [
+            ("toronto", "Toronto!", true),
+            ("toronto", "Torontonians", false),
+            ("toronto*", "Torontonians", true),
+            ("toronto*", "downtown", false),
+            ("*onto", "TORONTO", true),
+            ("*onto", "ontological", false),
+            ("*ront*", "Toronto", true),
+            ("montréal", "MONTRÉAL bagels", true),
+            ("montréal", "montreal bagels", false),
+            ("\"cn tower\"", "the CN Tower at night", true),
+            ("\"cn tower\"", "tower of cn", false),
+            ("yonge-dundas", "Yonge Dundas Square", true),
+            ("/yyz|pearson/", "flying out of YYZ", true),
+            ("/yyz|pearson/", "flying out of YTZ", false),
+            ("/^rt /", "RT this", true),
+            // A colon only makes a field prefix for a known field
+            ("10:30", "see you at 10:30", true),
+            ("10:30", "see you at 10", false),
+            ("https://blogto.com", "read https://blogto.com/x", true),
+            ("foo:bar", "foo bar", true),
+        ] {
+            assert_eq!(matches(rule, &text(post)), expected, "{:?} on {:?}", rule, post);
+        }
+    }
+
+    #[test]
+    fn operators() {
+        for (rule, post, expected) in [
+            ("raptors AND parade", "raptors parade", true),
+            ("raptors AND parade", "raptors game", false),
+            ("raptors parade", "parade for the raptors", true),
+            ("raptors parade", "raptors game", false),
+            ("raptors OR leafs", "leafs game", true),
+            ("raptors OR leafs", "jays game", false),
+            ("leafs NOT maple", "Leafs win", true),
+            ("leafs NOT maple", "Maple Leafs win", false),
+            // NOT binds tightest, then AND, then OR
+            ("NOT rain snow", "snow day", true),
+            ("NOT rain snow", "rain and snow", false),
+            ("rain OR snow day", "rain", true),
+            ("rain OR snow day", "snow", false),
+            ("rain OR snow day", "snow day", true),
+            ("(rain OR snow) day", "rain", false),
+            ("(rain OR snow) day", "rain day", true),
+            ("ttc NOT (delay OR closure)", "ttc is running fine", true),
+            ("ttc NOT (delay OR closure)", "ttc closure this weekend", false),
+        ] {
+            assert_eq!(matches(rule, &text(post)), expected, "{:?} on {:?}", rule, post);
+        }
+    }
+
+    #[test]
+    fn near() {
+        for (rule, post, expected) in [
+            ("queen NEAR/2 spadina", "queen and spadina", true),
+            ("queen NEAR/2 spadina", "spadina and queen", true),
+            ("queen NEAR/2 spadina", "queen street west, down to spadina", false),
+            ("queen NEAR/0 queen", "queen", true),
+            ("\"king st\" NEAR/3 bathurst", "King St. at Bathurst", true),
+            ("\"king st\" NEAR/3 bathurst", "King St. going west to Bathurst", false),
+            ("lake* NEAR/3 ontario", "lakeshore in ontario", true),
+        ] {
+            assert_eq!(matches(rule, &text(post)), expected, "{:?} on {:?}", rule, post);
+        }
+
+        // Both sides have to be in the same field
+        assert!(!matches("queen NEAR/5 spadina", &[(MatchSource::Text, "queen"), (MatchSource::ImageAlt, "spadina")]));
+    }
+
+    #[test]
+    fn field_scope() {
+        use MatchSource::*;
+        for (rule, post, expected) in [
+            ("alt:yyz", vec![(Text, "yyz")], false),
+            ("alt:yyz", vec![(ImageAlt, "yyz")], true),
+            ("alt:yyz", vec![(VideoAlt, "yyz")], true),
+            ("alt:yyz", vec![(Text, "photo"), (ImageAlt, "yyz")], true),
+            ("tag:(leafs OR jays)", vec![(Hashtag, "jays")], true),
+            ("tag:(leafs OR jays)", vec![(Tag, "leafs")], true),
+            ("tag:(leafs OR jays)", vec![(Text, "jays")], false),
+            ("link:blogto", vec![(CardUrl, "https://www.blogto.com/eat")], true),
+            ("title:\"cn tower\"", vec![(CardDescription, "cn tower")], false),
+            ("description:\"cn tower\"", vec![(CardDescription, "the CN Tower")], true),
+            ("alt:/pearson/", vec![(ImageAlt, "Pearson airport")], true),
+            ("alt:/pearson/", vec![(Text, "Pearson airport")], false),
+            // Only the term right after the prefix is scoped
+            ("alt:yyz airport", vec![(ImageAlt, "yyz"), (Text, "airport")], true),
+            ("alt:yyz airport", vec![(Text, "yyz airport")], false),
+            // NEAR takes its scope from the left side
+            ("alt:yyz NEAR/3 airport", vec![(ImageAlt, "yyz airport")], true),
+            ("alt:yyz NEAR/3 airport", vec![(Text, "yyz airport")], false),
+        ] {
+            assert_eq!(matches(rule, &post), expected, "{:?} on {:?}", rule, post);
+        }
+    }
+
+    #[test]
+    fn reports_where_it_matched() {
+        let mut matcher = Matcher::default();
+        matcher.add_rule("airport", "alt:yyz OR pearson").unwrap();
+        matcher.finish();
+        let fields = [(MatchSource::Text, "landed".to_string()), (MatchSource::ImageAlt, "the yyz arrivals board".to_string())];
+        let tokenized = tokenize(&fields);
+        let found: Vec<_> = matcher.matches(&tokenized, &Candidates::all()).collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].rule, "airport");
+        assert!(matches!(found[0].source, MatchSource::ImageAlt));
+        assert_eq!(found[0].snippet, "the yyz arrivals board");
+    }
+
+    #[test]
+    fn prefilter_agrees_with_full_eval() {
+        let rules = [
+            "toronto", "leafs*", "*onto", "*ront*", "\"cn tower\"", "\"dundas street west\"", "montréal",
+            "queen NEAR/2 spadina", "ttc NOT delay", "raptors OR (jays game)", "alt:yyz", "/yyz|pearson/ airport",
+        ];
+        let mut matcher = Matcher::default();
+        for rule in rules {
+            matcher.add_rule(rule, rule).unwrap();
+        }
+        assert_eq!(matcher.finish(), None);
+
+        for post in [
+            "Toronto", "TORONTO's best", "Torontonians", "the Leafs", "leafsnation", "Ontology", "moNTRéal",
+            "CN Tower", "cn-tower", "dundas street west", "Dundas Street", "Queen & Spadina", "ttc delay",
+            "ttc is fine", "jays game", "JAYS", "Pearson airport", "yyz", "nothing to see here", "",
+        ] {
+            let fields = [(MatchSource::Text, post.to_lowercase())];
+            let tokenized = tokenize(&fields);
+            let mut candidates = Candidates::default();
+            matcher.scan(post, &mut candidates);
+            let prefiltered: Vec<_> = matcher.matches(&tokenized, &candidates).map(|m| m.rule).collect();
+            let full: Vec<_> = matcher.matches(&tokenized, &Candidates::all()).map(|m| m.rule).collect();
+            assert_eq!(prefiltered, full, "on {:?}", post);
+        }
+    }
+}
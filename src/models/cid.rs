@@ -0,0 +1,126 @@
+use std::fmt;
+use std::str::FromStr;
+
+// RFC 4648 lowercase alphabet, which is what the "b" multibase prefix means
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// A content identifier, held as its raw binary form
+/// ([version][codec][hash type][hash len][hash]).
+///
+/// The same CID shows up in several encodings: DAG-CBOR tag 42 bytes in
+/// firehose ops, bare bytes at the front of CAR blocks, and base32 `bafy...`
+/// strings from the API and Jetstream. Everything we store uses the base32
+/// form that Display produces.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cid(Vec<u8>);
+
+#[derive(Debug)]
+pub struct CidError(String);
+
+impl fmt::Display for CidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid CID: {}", self.0)
+    }
+}
+
+impl std::error::Error for CidError {}
+
+impl Cid {
+    /// From the raw bytes used as CAR block keys
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CidError> {
+        match bytes.first() {
+            Some(1) => Ok(Cid(bytes.to_vec())),
+            Some(v) => Err(CidError(format!("unsupported version {}", v))),
+            None => Err(CidError("empty".to_string())),
+        }
+    }
+
+    /// From a DAG-CBOR tag 42 byte string, which carries a leading 0x00
+    /// (the identity multibase prefix)
+    pub fn from_tag42(bytes: &[u8]) -> Result<Self, CidError> {
+        match bytes.split_first() {
+            Some((0, rest)) => Cid::from_bytes(rest),
+            _ => Err(CidError("missing 0x00 multibase prefix".to_string())),
+        }
+    }
+
+    /// The raw bytes, matching how CIDs prefix CAR blocks
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::with_capacity(1 + (self.0.len() * 8).div_ceil(5));
+        out.push('b');
+
+        let mut buffer = 0u32;
+        let mut bits = 0;
+        for &byte in &self.0 {
+            buffer = (buffer << 8) | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+        }
+
+        f.write_str(&out)
+    }
+}
+
+impl FromStr for Cid {
+    type Err = CidError;
+
+    /// Accepts the base32 multibase form ("b..."), the base16 multibase form
+    /// ("f..."), and the bare lowercase hex we used to store in the cid column
+    fn from_str(s: &str) -> Result<Self, CidError> {
+        if let Some(rest) = s.strip_prefix('b').or_else(|| s.strip_prefix('B')) {
+            return Cid::from_bytes(&decode_base32(rest)?);
+        }
+        if let Some(rest) = s.strip_prefix('f') {
+            return Cid::from_bytes(&decode_hex(rest)?);
+        }
+        if s.starts_with("01") {
+            return Cid::from_bytes(&decode_hex(s)?);
+        }
+
+        Err(CidError(format!("unrecognised encoding: {}", s)))
+    }
+}
+
+fn decode_base32(s: &str) -> Result<Vec<u8>, CidError> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET.iter()
+            .position(|&a| a == c.to_ascii_lowercase())
+            .ok_or_else(|| CidError(format!("bad base32 character {:?}", c as char)))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(out)
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, CidError> {
+    if !s.len().is_multiple_of(2) {
+        return Err(CidError("odd length hex".to_string()));
+    }
+
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i + 2)
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .ok_or_else(|| CidError(format!("bad hex: {}", s))))
+        .collect()
+}
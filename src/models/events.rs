@@ -0,0 +1,45 @@
+// src/models/events.rs
+
+use serde::Deserialize;
+use super::Frame;
+
+/// Everything we act on from subscribeRepos, keyed off the frame header
+#[derive(Debug)]
+pub enum FirehoseMessage {
+    Commit(Frame),
+    Identity(IdentityEvent),
+    Account(AccountEvent),
+    Info(InfoEvent),
+    Error(ErrorFrame),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdentityEvent {
+    pub seq: i64,
+    pub did: String,
+    pub time: String,
+    pub handle: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountEvent {
+    pub seq: i64,
+    pub did: String,
+    pub time: String,
+    pub active: bool,
+    // takendown, suspended, deleted, deactivated, ...
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InfoEvent {
+    pub name: String,
+    pub message: Option<String>,
+}
+
+/// Body of an op = -1 frame. The relay closes the connection after sending one.
+#[derive(Debug, Deserialize)]
+pub struct ErrorFrame {
+    pub error: String,
+    pub message: Option<String>,
+}
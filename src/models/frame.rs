@@ -9,5 +9,10 @@ pub struct Frame {
     pub ops: Vec<Operation>,
     #[serde(with = "serde_bytes")]
     pub blocks: Vec<u8>,
+    // Link to the signed commit object inside blocks
+    #[serde(default)]
+    #[serde(with = "serde_bytes")]
+    pub commit: Option<Vec<u8>>,
     pub seq: i64,
+    pub time: String,
 }
\ No newline at end of file
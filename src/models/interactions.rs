@@ -9,6 +9,25 @@ pub enum InteractionType {
     REPOST
 }
 
+impl InteractionType {
+    /// Maps a record path like "app.bsky.feed.like/<rkey>" to its type and rkey
+    pub fn from_path(path: &str) -> Option<(InteractionType, &str)> {
+        let (collection, rkey) = path.split_once('/')?;
+        match collection {
+            "app.bsky.feed.like" => Some((InteractionType::LIKE, rkey)),
+            "app.bsky.feed.repost" => Some((InteractionType::REPOST, rkey)),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InteractionType::LIKE => "like",
+            InteractionType::REPOST => "repost",
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Like {
     pub subject: StrongRef,
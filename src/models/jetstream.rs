@@ -0,0 +1,25 @@
+// src/models/jetstream.rs
+
+use serde::Deserialize;
+use super::{Action, AccountEvent, IdentityEvent};
+
+/// One JSON event from Jetstream's /subscribe endpoint
+#[derive(Debug, Deserialize)]
+pub struct JetstreamEvent {
+    pub did: String,
+    pub time_us: i64,
+    pub kind: String,
+    pub commit: Option<JetstreamCommit>,
+    pub identity: Option<IdentityEvent>,
+    pub account: Option<AccountEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JetstreamCommit {
+    pub operation: Action,
+    pub collection: String,
+    pub rkey: String,
+    // Only present on create/update
+    pub record: Option<serde_json::Value>,
+    pub cid: Option<String>,
+}
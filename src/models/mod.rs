@@ -2,8 +2,16 @@ mod frame;
 mod operation;
 mod post;
 mod interactions;
+mod jetstream;
+mod events;
+mod cid;
+mod repo;
 
 pub use frame::Frame;
+pub use cid::Cid;
+pub use repo::{Commit, MstNode};
+pub use events::{FirehoseMessage, IdentityEvent, AccountEvent};
 pub use operation::{Action, Operation};
-pub use post::{Post, Facet, Feature, Embed, Image, Reply, StrongRef, TorontoPost};
-pub use interactions::{Like, Repost, InteractionType};
\ No newline at end of file
+pub use post::{Post, Facet, Feature, Embed, Image, Reply, StrongRef, TorontoPost, MatchReason};
+pub use interactions::{Like, Repost, InteractionType};
+pub use jetstream::JetstreamEvent;
\ No newline at end of file
@@ -13,6 +13,25 @@ pub struct Post {
     pub labels: Option<SelfLabels>
 }
 
+impl Post {
+    /// The post this one quotes, with or without media attached
+    pub fn quoted_uri(&self) -> Option<&str> {
+        match &self.embed {
+            Some(Embed::Record { record }) => Some(&record.uri),
+            Some(Embed::RecordWithMedia { record, .. }) => Some(&record.record.uri),
+            _ => None,
+        }
+    }
+
+    /// The post's own media, looking inside a quote's attached media
+    pub fn media(&self) -> Option<&Embed> {
+        match &self.embed {
+            Some(Embed::RecordWithMedia { media, .. }) => media.as_deref(),
+            embed => embed.as_ref(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Facet {
     pub features: Vec<Feature>,
@@ -69,6 +88,17 @@ pub enum Embed {
     Images {
         images: Vec<Image>
     },
+    #[serde(rename = "app.bsky.embed.recordWithMedia")]
+    RecordWithMedia {
+        record: EmbeddedRecord,
+        /// Images, a video or a link card
+        #[serde(default)]
+        media: Option<Box<Embed>>,
+    },
+    #[serde(rename = "app.bsky.embed.external")]
+    External {
+        external: ExternalLink
+    },
     #[serde(rename = "app.bsky.embed.video")]
     Video {
         // captions: Option<Vec<Captions>>,
@@ -78,6 +108,22 @@ pub enum Embed {
     Unknown
 }
 
+/// A link card
+#[derive(Debug, Deserialize)]
+pub struct ExternalLink {
+    pub uri: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// The app.bsky.embed.record inside a recordWithMedia
+#[derive(Debug, Deserialize)]
+pub struct EmbeddedRecord {
+    pub record: StrongRef,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AspectRatio {
     pub width: i32,
@@ -97,6 +143,23 @@ pub struct TorontoPost {
     pub did: String,
     pub indexed_at: i64,
     pub created_at: i64,
+    pub reply_parent: Option<String>,
+    pub reply_root: Option<String>,
+    pub reason: MatchReason,
+}
+
+/// Why a post is in the feed
+#[derive(Debug, Clone, Default)]
+pub struct MatchReason {
+    /// Every keyword or rule that matched, empty for quotes and replies
+    pub rules: Vec<String>,
+    /// Where the first rule matched (e.g. "text", "hashtag", "image_alt"),
+    /// "quote"/"reply" for posts let in by the post they quote or reply to, or
+    /// "search" for backfilled posts only the search API matched
+    pub field: String,
+    /// The matched text with some context, the quoted/parent post's URI, or
+    /// the start of the text for "search"
+    pub snippet: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -0,0 +1,43 @@
+// src/models/repo.rs
+
+use serde::Deserialize;
+
+// CID links below are DAG-CBOR tag 42 byte strings, so they keep their
+// leading 0x00 and go through Cid::from_tag42
+
+/// The signed commit object a #commit frame points at
+#[derive(Debug, Deserialize)]
+pub struct Commit {
+    pub did: String,
+    pub version: i64,
+    // Root of the repo's MST after this commit
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub sig: Vec<u8>,
+}
+
+/// One node of a repo's Merkle Search Tree
+#[derive(Debug, Deserialize)]
+pub struct MstNode {
+    // Subtree with keys sorting before the first entry
+    #[serde(default)]
+    #[serde(with = "serde_bytes")]
+    pub l: Option<Vec<u8>>,
+    pub e: Vec<TreeEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TreeEntry {
+    // Bytes shared with the previous entry's key, followed by the rest of it
+    pub p: usize,
+    #[serde(with = "serde_bytes")]
+    pub k: Vec<u8>,
+    // Record CID
+    #[serde(with = "serde_bytes")]
+    pub v: Vec<u8>,
+    // Subtree with keys between this entry and the next
+    #[serde(default)]
+    #[serde(with = "serde_bytes")]
+    pub t: Option<Vec<u8>>,
+}
@@ -1,87 +1,213 @@
 use serde::Deserialize;
-use crate::models::Frame;
+use std::collections::HashMap;
+use crate::models::FirehoseMessage;
+
+// Real records are capped well below this, anything bigger is corrupt or hostile
+const MAX_BLOCK_BYTES: u64 = 2 * 1024 * 1024;
+// Repo objects nest a handful of levels, this only guards the stack
+const MAX_CBOR_DEPTH: usize = 64;
+
+/// Why a frame couldn't be decoded. Every bad input ends up here instead of
+/// panicking, so one broken frame can't take down ingestion.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The CAR header length is missing or runs past the end of the data
+    TruncatedHeader,
+    /// A varint is unterminated or doesn't fit in 64 bits
+    BadVarint,
+    /// A block's CID is malformed or longer than the block itself
+    BadCid,
+    /// A block claims more than MAX_BLOCK_BYTES
+    OversizedBlock { len: u64 },
+    /// A block claims more bytes than are left in the CAR
+    TruncatedBlock { len: u64, available: usize },
+    /// The frame's CBOR header or body didn't decode
+    Cbor(serde_cbor::Error),
+    /// A raw CBOR item we walk by hand is malformed
+    BadItem(&'static str),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::TruncatedHeader => write!(f, "truncated CAR header"),
+            ParseError::BadVarint => write!(f, "bad varint"),
+            ParseError::BadCid => write!(f, "bad CID"),
+            ParseError::OversizedBlock { len } => write!(f, "oversized block ({} bytes)", len),
+            ParseError::TruncatedBlock { len, available } =>
+                write!(f, "truncated block ({} bytes claimed, {} available)", len, available),
+            ParseError::Cbor(e) => write!(f, "bad CBOR: {}", e),
+            ParseError::BadItem(reason) => write!(f, "bad CBOR item: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<serde_cbor::Error> for ParseError {
+    fn from(e: serde_cbor::Error) -> Self {
+        ParseError::Cbor(e)
+    }
+}
 
 // Header tells us what type of message this is
 #[derive(Debug, Deserialize)]
 struct Header {
-    op: i32,      // operation type (1 = message)
-    t: String,    // message type ("#commit", "#identity", etc.)
+    op: i32,            // operation type (1 = message, -1 = error)
+    t: Option<String>,  // message type ("#commit", "#identity", etc.), absent on errors
+}
+
+pub fn parse_message(data: &[u8]) -> Result<Option<FirehoseMessage>, ParseError> {
+    // A frame is two CBOR values back to back. Deserializing straight into the
+    // typed structs avoids building (and copying the blocks into) a Value tree.
+    let mut de = serde_cbor::Deserializer::from_slice(data);
+
+    let header = Header::deserialize(&mut de)?;
+
+    let is_error = header.op == -1;
+    if !is_error && !matches!(header.t.as_deref(), Some("#commit" | "#identity" | "#account" | "#info")) {
+        return Ok(None);  // #sync etc, skip
+    }
+
+    let message = if is_error {
+        FirehoseMessage::Error(Deserialize::deserialize(&mut de)?)
+    } else {
+        match header.t.as_deref() {
+            Some("#commit") => FirehoseMessage::Commit(Deserialize::deserialize(&mut de)?),
+            Some("#identity") => FirehoseMessage::Identity(Deserialize::deserialize(&mut de)?),
+            Some("#account") => FirehoseMessage::Account(Deserialize::deserialize(&mut de)?),
+            _ => FirehoseMessage::Info(Deserialize::deserialize(&mut de)?),
+        }
+    };
+
+    Ok(Some(message))
 }
 
-pub fn parse_message(data: &[u8]) -> Result<Option<Frame>, Box<dyn std::error::Error>> {
-    let mut iter = serde_cbor::Deserializer::from_slice(data).into_iter::<serde_cbor::Value>();
+/// Reads a CBOR item head, returning (major type, argument, head size). For
+/// strings, arrays and maps the argument is the length; for tags, the tag number.
+pub fn read_cbor_head(data: &[u8]) -> Result<(u8, u64, usize), ParseError> {
+    let truncated = || ParseError::BadItem("truncated item");
+    let first = *data.first().ok_or_else(truncated)?;
+    let major = first >> 5;
 
-    let header_val = iter.next().ok_or("No header")??;
-    let header: Header = serde_cbor::value::from_value(header_val)?;
+    let (arg, size) = match first & 0x1f {
+        n @ 0..=23 => (n as u64, 1),
+        24 => (*data.get(1).ok_or_else(truncated)? as u64, 2),
+        25 => (u16::from_be_bytes(data.get(1..3).ok_or_else(truncated)?.try_into().unwrap()) as u64, 3),
+        26 => (u32::from_be_bytes(data.get(1..5).ok_or_else(truncated)?.try_into().unwrap()) as u64, 5),
+        27 => (u64::from_be_bytes(data.get(1..9).ok_or_else(truncated)?.try_into().unwrap()), 9),
+        // DAG-CBOR forbids indefinite lengths
+        _ => return Err(ParseError::BadItem("unsupported item length")),
+    };
+
+    Ok((major, arg, size))
+}
 
-    if header.t != "#commit" {
-        return Ok(None);  // Not a commit, skip
+/// Byte length of the complete CBOR item at the start of data, without
+/// decoding it. Lets us slice entries out of a block as they were encoded.
+pub fn cbor_item_len(data: &[u8]) -> Result<usize, ParseError> {
+    item_len(data, 0)
+}
+
+fn item_len(data: &[u8], depth: usize) -> Result<usize, ParseError> {
+    if depth > MAX_CBOR_DEPTH {
+        return Err(ParseError::BadItem("nested too deeply"));
     }
+    let (major, arg, mut pos) = read_cbor_head(data)?;
+
+    let children = match major {
+        0 | 1 | 7 => 0,           // ints, simple values and floats are all head
+        2 | 3 => {                // byte and text strings
+            if arg > (data.len() - pos) as u64 {
+                return Err(ParseError::BadItem("truncated string"));
+            }
+            pos += arg as usize;
+            0
+        },
+        4 => arg,                 // array: arg items
+        5 => arg.saturating_mul(2), // map: arg key/value pairs
+        _ => 1,                   // tag: one tagged item
+    };
 
-    let frame_val = iter.next().ok_or("Frame value missing")??;
-    let frame = serde_cbor::value::from_value(frame_val)?;
+    for _ in 0..children {
+        pos += item_len(&data[pos..], depth + 1)?;
+    }
 
-    Ok(Some(frame))
+    Ok(pos)
 }
 
-fn split_cid_and_data(block: &[u8]) -> (Vec<u8>, Vec<u8>) {
+fn split_cid_and_data(block: &[u8]) -> Result<(&[u8], &[u8]), ParseError> {
     // CID structure:
     // [version varint][codec varint][hash_type varint][hash_len varint][hash_bytes]
     let mut pos = 0;
 
-    let (_, size) = read_varint(&block[pos..]);
+    let (_, size) = read_varint(&block[pos..]).map_err(|_| ParseError::BadCid)?;
     pos += size;
 
-    let (_, size) = read_varint(&block[pos..]);
+    let (_, size) = read_varint(&block[pos..]).map_err(|_| ParseError::BadCid)?;
     pos += size;
 
     // 3. Read hash type (varint, 0x12 for sha256)
-    let (_, size) = read_varint(&block[pos..]);
+    let (_, size) = read_varint(&block[pos..]).map_err(|_| ParseError::BadCid)?;
     pos += size;
 
     // 4. Read hash length (varint, 0x20 = 32)
-    let (hash_len, size) = read_varint(&block[pos..]);
+    let (hash_len, size) = read_varint(&block[pos..]).map_err(|_| ParseError::BadCid)?;
     pos += size;
 
-    // 5. Skip hash bytes
+    // 5. Skip hash bytes, which must fit inside the block
+    if hash_len > (block.len() - pos) as u64 {
+        return Err(ParseError::BadCid);
+    }
     pos += hash_len as usize;
 
     // Now pos points to where DATA begins
-    let cid = block[..pos].to_vec();
-    let data = block[pos..].to_vec();
-
-    (cid, data)
+    Ok(block.split_at(pos))
 }
 
-pub fn parse_car_blocks(car_data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {  // Vec<(CID, Data)>
-    let mut blocks = Vec::new();
+/// Decodes a CAR file into its blocks, keyed by raw CID bytes. Both keys and
+/// values borrow from car_data, so this allocates nothing per block.
+pub fn parse_car_blocks(car_data: &[u8]) -> Result<HashMap<&[u8], &[u8]>, ParseError> {  // CID -> Data
+    let mut blocks = HashMap::new();
     let mut pos = 0;
 
     // 1. Skip header
-    let (header_len, varint_size) = read_varint(&car_data[pos..]);
-    pos += varint_size + header_len as usize;
+    let (header_len, varint_size) = read_varint(car_data).map_err(|_| ParseError::TruncatedHeader)?;
+    pos += varint_size;
+    if header_len > (car_data.len() - pos) as u64 {
+        return Err(ParseError::TruncatedHeader);
+    }
+    pos += header_len as usize;
 
     // 2. Read blocks until end
     while pos < car_data.len() {
         // Read block length
-        let (block_len, varint_size) = read_varint(&car_data[pos..]);
+        let (block_len, varint_size) = read_varint(&car_data[pos..])?;
         pos += varint_size;
 
+        let available = car_data.len() - pos;
+        if block_len > MAX_BLOCK_BYTES {
+            return Err(ParseError::OversizedBlock { len: block_len });
+        }
+        if block_len > available as u64 {
+            return Err(ParseError::TruncatedBlock { len: block_len, available });
+        }
+
         // Block contains: [CID][DATA]
         // We need to parse CID to know where DATA starts
         let block_bytes = &car_data[pos..pos + block_len as usize];
 
         // Parse CID, get remaining as data
-        let (cid, data) = split_cid_and_data(block_bytes);
-        blocks.push((cid, data));
+        let (cid, data) = split_cid_and_data(block_bytes)?;
+        blocks.insert(cid, data);
 
         pos += block_len as usize;
     }
 
-    blocks
+    Ok(blocks)
 }
 
-pub fn read_varint(data: &[u8]) -> (u64, usize) {
+pub fn read_varint(data: &[u8]) -> Result<(u64, usize), ParseError> {
     // Each byte:
     // - Lower 7 bits: part of the number
     // - High bit (0x80): "more bytes follow" flag
@@ -97,14 +223,165 @@ pub fn read_varint(data: &[u8]) -> (u64, usize) {
 
     for (i, &byte) in data.iter().enumerate() {
         let data_bits = (0x7F & byte) as u64;
+
+        // A u64 fits in 10 bytes, and the 10th may only carry the top bit
+        if shift == 63 && data_bits > 1 {
+            return Err(ParseError::BadVarint);
+        }
         result |= data_bits << shift;
 
         if (0x80 & byte) == 0 {
-            return (result, i + 1);
+            return Ok((result, i + 1));
         }
 
         shift += 7;
+        if shift > 63 {
+            return Err(ParseError::BadVarint);
+        }
     };
 
-    (result, data.len())
+    // Ran out of bytes with the continuation bit still set
+    Err(ParseError::BadVarint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn varint(mut n: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(byte);
+                return out;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    /// A CIDv1 prefix (dag-cbor, sha2-256) with a made up digest
+    fn cid(seed: u8) -> Vec<u8> {
+        [&[0x01, 0x71, 0x12, 0x20][..], &[seed; 32]].concat()
+    }
+
+    /// A CAR with a small header and the given (CID, data) blocks
+    fn car(blocks: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let header = [0xa2, 0x65, b'r', b'o', b'o', b't', b's', 0x80, 0x67, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x01];
+        let mut out = varint(header.len() as u64);
+        out.extend_from_slice(&header);
+        for (cid, data) in blocks {
+            out.extend(varint((cid.len() + data.len()) as u64));
+            out.extend_from_slice(cid);
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    #[test]
+    fn reads_blocks() {
+        let data = car(&[(cid(1), b"one".to_vec()), (cid(2), b"two".to_vec())]);
+        let blocks = parse_car_blocks(&data).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[cid(1).as_slice()], b"one");
+        assert_eq!(blocks[cid(2).as_slice()], b"two");
+    }
+
+    #[test]
+    fn truncated_header() {
+        assert!(matches!(parse_car_blocks(&[]), Err(ParseError::TruncatedHeader)));
+        assert!(matches!(parse_car_blocks(&[0x80]), Err(ParseError::TruncatedHeader)));
+        assert!(matches!(parse_car_blocks(&[0x05, 0xa0]), Err(ParseError::TruncatedHeader)));
+    }
+
+    #[test]
+    fn varint_overflow() {
+        assert_eq!(read_varint(&varint(u64::MAX)).unwrap(), (u64::MAX, 10));
+        // 10th byte carrying more than the top bit
+        let mut too_big = vec![0xff; 9];
+        too_big.push(0x02);
+        assert!(matches!(read_varint(&too_big), Err(ParseError::BadVarint)));
+        // Continuation bit set past 10 bytes
+        assert!(matches!(read_varint(&[0x80; 11]), Err(ParseError::BadVarint)));
+        // Ends mid-varint
+        assert!(matches!(read_varint(&[0x80, 0x80]), Err(ParseError::BadVarint)));
+        assert!(matches!(read_varint(&[]), Err(ParseError::BadVarint)));
+    }
+
+    #[test]
+    fn bad_cid_prefix() {
+        // Hash length runs past the end of the block
+        let long_hash = [&[0x01, 0x71, 0x12, 0x40][..], &[0; 32]].concat();
+        assert!(matches!(parse_car_blocks(&car(&[(long_hash, Vec::new())])), Err(ParseError::BadCid)));
+        // Block ends in the middle of the CID's varints
+        assert!(matches!(parse_car_blocks(&car(&[(vec![0x01, 0x71, 0x92], Vec::new())])), Err(ParseError::BadCid)));
+    }
+
+    #[test]
+    fn oversized_block() {
+        let mut data = car(&[]);
+        data.extend(varint(MAX_BLOCK_BYTES + 1));
+        data.extend_from_slice(&[0; 64]);
+        assert!(matches!(parse_car_blocks(&data), Err(ParseError::OversizedBlock { len }) if len == MAX_BLOCK_BYTES + 1));
+    }
+
+    #[test]
+    fn truncated_block() {
+        let mut data = car(&[(cid(1), b"one".to_vec())]);
+        data.pop();
+        assert!(matches!(parse_car_blocks(&data), Err(ParseError::TruncatedBlock { len: 39, available: 38 })));
+    }
+
+    #[test]
+    fn cbor_items() {
+        // [1, "ab", {"k": h'00'}]
+        let item = [0x83, 0x01, 0x62, b'a', b'b', 0xa1, 0x61, b'k', 0x41, 0x00];
+        assert_eq!(cbor_item_len(&[&item[..], &[0xff; 4]].concat()).unwrap(), item.len());
+        assert!(matches!(cbor_item_len(&item[..item.len() - 1]), Err(ParseError::BadItem(_))));
+        // A string claiming more bytes than there are
+        assert!(matches!(cbor_item_len(&[0x5a, 0xff, 0xff, 0xff, 0xff]), Err(ParseError::BadItem(_))));
+        // Nested past MAX_CBOR_DEPTH
+        assert!(matches!(cbor_item_len(&[0x81; 1000]), Err(ParseError::BadItem("nested too deeply"))));
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = parse_message(&data);
+            let _ = parse_car_blocks(&data);
+            let _ = cbor_item_len(&data);
+            let _ = read_varint(&data);
+        }
+
+        #[test]
+        fn varint_round_trips(n in any::<u64>()) {
+            let encoded = varint(n);
+            prop_assert_eq!(read_varint(&encoded).unwrap(), (n, encoded.len()));
+        }
+
+        #[test]
+        fn cars_round_trip(blocks in proptest::collection::vec((any::<u8>(), proptest::collection::vec(any::<u8>(), 0..64)), 0..8)) {
+            let blocks: HashMap<Vec<u8>, Vec<u8>> = blocks.into_iter().map(|(seed, data)| (cid(seed), data)).collect();
+            let blocks: Vec<(Vec<u8>, Vec<u8>)> = blocks.into_iter().collect();
+            let data = car(&blocks);
+
+            let parsed = parse_car_blocks(&data).unwrap();
+            prop_assert_eq!(parsed.len(), blocks.len());
+            for (cid, block) in &blocks {
+                prop_assert_eq!(parsed[cid.as_slice()], block.as_slice());
+            }
+        }
+
+        #[test]
+        fn truncated_cars_error(blocks in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 1..64), 1..8), cut in any::<prop::sample::Index>()) {
+            let blocks: Vec<(Vec<u8>, Vec<u8>)> = blocks.into_iter().enumerate().map(|(i, data)| (cid(i as u8), data)).collect();
+            let data = car(&blocks);
+            // Cut inside the last block, which always leaves it short
+            let last = data.len() - blocks.last().unwrap().1.len();
+            let cut = last + cut.index(data.len() - last);
+            prop_assert!(parse_car_blocks(&data[..cut]).is_err());
+        }
+    }
 }
@@ -0,0 +1,146 @@
+use crate::filter::{Event, Filter};
+use crate::ingestion::{decode_frame, Decoded, FrameOutcome};
+use crate::verify::Verifier;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Frames read but not yet decoded. Once full the reader stops pulling from the
+// socket, so a stalled writer shows up here before the relay drops us.
+const DECODE_QUEUE_FRAMES: usize = 10_000;
+// Decoded frames waiting on the writer
+const WRITE_QUEUE_FRAMES: usize = 10_000;
+const QUEUE_REPORT_SECS: u64 = 30;
+
+/// TOSKY_WORKERS decode threads, by default one per core left over after the
+/// reader and writer
+pub fn worker_count() -> usize {
+    std::env::var("TOSKY_WORKERS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get().saturating_sub(2)))
+        .max(1)
+}
+
+/// How deep the queues got, and how long the workers spent decoding
+#[derive(Default)]
+pub struct QueueStats {
+    decode: AtomicUsize,
+    write: AtomicUsize,
+    pub max_decode: AtomicUsize,
+    pub max_write: AtomicUsize,
+    pub decode_nanos: AtomicU64,
+}
+
+impl QueueStats {
+    fn push(depth: &AtomicUsize, max: &AtomicUsize) {
+        let now = depth.fetch_add(1, Ordering::Relaxed) + 1;
+        max.fetch_max(now, Ordering::Relaxed);
+    }
+}
+
+/// Runs frames through three stages: a reader thread that only calls
+/// next_frame, worker threads that decode, verify and keyword-match, and this
+/// thread, which applies the results to the filter (and so the database) and
+/// invalidates signing keys on #identity events.
+///
+/// Workers finish out of order, so results are held until every earlier frame
+/// has been applied. Frames reach the filter, and on_frame, in exactly the
+/// order they were read, which keeps each repo's ops in order and means the
+/// cursor only ever advances past frames that are fully handled.
+///
+/// Stops when next_frame runs out or on_frame returns false. In that case
+/// on_frame must also unblock a next_frame that's waiting on the network.
+pub fn run<R, F, W>(filter: &mut Filter, verifier: &Verifier, mut next_frame: R, mut on_frame: W) -> QueueStats
+where
+    R: FnMut() -> Option<F> + Send,
+    F: AsRef<[u8]> + Send,
+    W: FnMut(&mut Filter, FrameOutcome) -> bool,
+{
+    let stats = QueueStats::default();
+    let stop = AtomicBool::new(false);
+    let (work_tx, work_rx) = sync_channel::<(u64, F)>(DECODE_QUEUE_FRAMES);
+    let work_rx = Mutex::new(work_rx);
+    let (done_tx, done_rx) = sync_channel::<(u64, Decoded)>(WRITE_QUEUE_FRAMES);
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut index = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let Some(frame) = next_frame() else { break };
+                QueueStats::push(&stats.decode, &stats.max_decode);
+                if work_tx.send((index, frame)).is_err() {
+                    break;
+                }
+                index += 1;
+            }
+            drop(work_tx);
+        });
+
+        for _ in 0..worker_count() {
+            let done_tx = done_tx.clone();
+            let (work_rx, stats, stop) = (&work_rx, &stats, &stop);
+            scope.spawn(move || loop {
+                let job = work_rx.lock().unwrap().recv();
+                let Ok((index, frame)) = job else { break };
+                stats.decode.fetch_sub(1, Ordering::Relaxed);
+
+                // Keep draining after a stop so the reader never blocks on a full queue
+                if stop.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                let started = Instant::now();
+                let decoded = decode_frame(verifier, frame.as_ref());
+                stats.decode_nanos.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+
+                QueueStats::push(&stats.write, &stats.max_write);
+                if done_tx.send((index, decoded)).is_err() {
+                    stats.write.fetch_sub(1, Ordering::Relaxed);
+                }
+            });
+        }
+        drop(done_tx);
+
+        let mut waiting: BTreeMap<u64, Decoded> = BTreeMap::new();
+        let mut next = 0;
+        let mut last_report = Instant::now();
+
+        'writer: for (index, decoded) in done_rx.iter() {
+            waiting.insert(index, decoded);
+
+            while let Some(decoded) = waiting.remove(&next) {
+                next += 1;
+                stats.write.fetch_sub(1, Ordering::Relaxed);
+
+                for event in decoded.events {
+                    // In stream order here rather than on a worker. Commits
+                    // signed with a new key don't need it, they miss the
+                    // cache and resolve the DID again either way.
+                    if let Event::Identity { did, .. } = &event {
+                        verifier.commits.forget(did);
+                    }
+                    filter.apply(event);
+                }
+                if !on_frame(filter, decoded.outcome) {
+                    stop.store(true, Ordering::Relaxed);
+                    break 'writer;
+                }
+            }
+
+            if last_report.elapsed() >= Duration::from_secs(QUEUE_REPORT_SECS) {
+                println!("[Ingestion] Queues: {} frames to decode, {} to write ({} held for ordering)",
+                    stats.decode.load(Ordering::Relaxed), stats.write.load(Ordering::Relaxed), waiting.len());
+                last_report = Instant::now();
+            }
+        }
+
+        // Lets workers blocked on a full write queue move on and drain
+        drop(done_rx);
+    });
+
+    stats
+}
@@ -0,0 +1,165 @@
+use crate::db::{Database, Metadata};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const RELAY_RETENTION_SECS: i64 = 259200; // 3 days, roughly how far back relays can replay
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+const RELAY_COOLDOWN_SECS: u64 = 300;    // How long a failed relay sits out before we retry it
+
+pub struct Relay {
+    pub url: String,
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl Relay {
+    fn new(url: &str) -> Self {
+        Relay {
+            url: url.trim_end_matches('/').to_string(),
+            failures: 0,
+            down_until: None,
+        }
+    }
+
+    /// Sequence numbers aren't portable between relays, so each keeps its own cursor
+    pub fn cursor_key(&self) -> String {
+        let host = self.url.split("://").last().unwrap_or(&self.url);
+        format!("cursor:{}", host)
+    }
+
+    pub fn subscribe_url(&self, cursor: Option<i64>) -> String {
+        match cursor {
+            Some(seq) => format!("{}/xrpc/com.atproto.sync.subscribeRepos?cursor={}", self.url, seq),
+            None => format!("{}/xrpc/com.atproto.sync.subscribeRepos", self.url),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.down_until.is_none_or(|until| Instant::now() >= until)
+    }
+}
+
+pub struct RelayPool {
+    relays: Vec<Relay>,
+    current: usize,
+}
+
+impl RelayPool {
+    /// Reads a comma separated list of relay URLs from TOSKY_RELAYS, in order of preference
+    pub fn from_env() -> Self {
+        let urls = std::env::var("TOSKY_RELAYS")
+            .unwrap_or_else(|_| "wss://bsky.network".to_string());
+
+        let relays: Vec<Relay> = urls.split(',')
+            .map(|u| u.trim())
+            .filter(|u| !u.is_empty())
+            .map(Relay::new)
+            .collect();
+
+        if relays.is_empty() {
+            return RelayPool { relays: vec![Relay::new("wss://bsky.network")], current: 0 };
+        }
+
+        RelayPool { relays, current: 0 }
+    }
+
+    pub fn current(&self) -> &Relay {
+        &self.relays[self.current]
+    }
+
+    /// Called once a connection is actually delivering frames
+    pub fn record_success(&mut self) {
+        let relay = &mut self.relays[self.current];
+        relay.failures = 0;
+        relay.down_until = None;
+    }
+
+    /// Counts a connect failure or dropped/timed out read. After enough
+    /// consecutive failures the relay sits out and we move to the next one.
+    pub fn record_failure(&mut self) {
+        let single_relay = self.relays.len() == 1;
+        let relay = &mut self.relays[self.current];
+        relay.failures += 1;
+
+        if relay.failures < MAX_CONSECUTIVE_FAILURES || single_relay {
+            return;
+        }
+
+        println!("[Ingestion] {} failed {} times in a row, failing over",
+            relay.url, relay.failures);
+        relay.failures = 0;
+        relay.down_until = Some(Instant::now() + Duration::from_secs(RELAY_COOLDOWN_SECS));
+
+        if let Some(next) = (1..self.relays.len())
+            .map(|i| (self.current + i) % self.relays.len())
+            .find(|&i| self.relays[i].is_healthy()) {
+            self.current = next;
+        }
+    }
+
+    /// Picks the relay to connect to and the cursor to resume from. Prefers the
+    /// current relay, then any other healthy relay with a usable cursor, and
+    /// only starts fresh when no relay has one.
+    pub fn select(&mut self, db: &Database) -> Option<i64> {
+        if let Some(seq) = usable_cursor(db, self.current()) {
+            return Some(seq);
+        }
+
+        for i in 0..self.relays.len() {
+            if i == self.current || !self.relays[i].is_healthy() {
+                continue;
+            }
+
+            if let Some(seq) = usable_cursor(db, &self.relays[i]) {
+                println!("[Ingestion] Switching to {} which has a usable cursor", self.relays[i].url);
+                self.current = i;
+                return Some(seq);
+            }
+        }
+
+        println!("[Ingestion] No relay has a usable cursor, starting fresh on {}", self.current().url);
+        None
+    }
+}
+
+fn usable_cursor(db: &Database, relay: &Relay) -> Option<i64> {
+    let meta = db.get_metadata(&relay.cursor_key())?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let age = now - meta.last_updated;
+
+    if meta.last_updated > 0 && age > RELAY_RETENTION_SECS {
+        println!("[Ingestion] Cursor for {} is {}s old (>{} max), discarding stale cursor",
+            relay.url, age, RELAY_RETENTION_SECS);
+        report_gap(db, &relay.url, &meta);
+        // Reported once, not again on every reconnect
+        db.delete_metadata(&relay.cursor_key());
+        return None;
+    }
+
+    println!("[Ingestion] Found cursor for {}: {} (age: {}s)", relay.url, meta.seq, age);
+    Some(meta.seq)
+}
+
+/// Logs how much history was lost because our cursor fell outside the relay's
+/// retention window, and records it under `last_gap` (seq = seconds missed)
+pub fn report_gap(db: &Database, relay_url: &str, meta: &Metadata) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let oldest_available = now - RELAY_RETENTION_SECS;
+    let missed = (oldest_available - meta.last_updated).max(0);
+
+    let fmt = |ts: i64| chrono::DateTime::from_timestamp(ts, 0)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| ts.to_string());
+
+    println!("[Ingestion] Gap report for {}: cursor {} points at {}, but the relay only keeps events since ~{}. \
+        About {}h of events are unrecoverable (backfill only covers the last 48h).",
+        relay_url, meta.seq, fmt(meta.last_updated), fmt(oldest_available), missed / 3600);
+
+    db.set_metadata("last_gap", &Metadata { seq: missed, last_updated: now });
+}
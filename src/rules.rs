@@ -0,0 +1,487 @@
+use crate::filter::MatchSource;
+use crate::gazetteer::Gazetteer;
+use crate::matcher::{tokenize, Candidates, Matcher};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::sync::mpsc;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// The rules we ship with, used when TOSKY_RULES_PATH isn't set
+const DEFAULT_RULES: &str = include_str!("../rules.json");
+const RELOAD_CHECK_SECS: u64 = 5;
+
+static RULES: OnceLock<RwLock<Arc<Rules>>> = OnceLock::new();
+
+/// The rule file as written. See rules.json and the README for the format.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    lax_keywords: Vec<String>,
+    #[serde(default)]
+    strict_keywords: Vec<String>,
+    #[serde(default)]
+    rules: Vec<RuleDef>,
+    #[serde(default)]
+    exclusions: Vec<ExclusionDef>,
+    nsfw_labels: Vec<String>,
+    /// Whether to match the place names in gazetteer.json too, on unless false
+    gazetteer: Option<bool>,
+}
+
+/// A named expression in the rule language, see matcher.rs
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleDef {
+    name: String,
+    #[serde(rename = "match")]
+    expr: String,
+}
+
+/// Keeps posts out of the feed even though they matched. Any of the three
+/// can be given, and a post is excluded if any of them hits.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExclusionDef {
+    name: String,
+    #[serde(rename = "match")]
+    expr: Option<String>,
+    /// Link and link card hosts, subdomains included
+    #[serde(default)]
+    domains: Vec<String>,
+    /// Authors, e.g. known bot accounts
+    #[serde(default)]
+    dids: Vec<String>,
+}
+
+/// What the filter matches on. Loaded from a JSON file so it can be tweaked
+/// without a rebuild, and swapped out live when the file changes.
+pub struct Rules {
+    /// Matched anywhere in the text, also used as backfill search terms
+    pub lax_keywords: Vec<String>,
+    /// Matched as whole words or phrases, also used as backfill search terms
+    pub strict_keywords: Vec<String>,
+    /// Self-labels that keep a post out of the feed
+    pub nsfw_labels: Vec<String>,
+    /// Keywords then rules, compiled
+    pub matcher: Matcher,
+    pub exclusions: Exclusions,
+    rule_count: usize,
+    /// Gazetteer names added to the matcher, 0 if it's turned off
+    gazetteer_names: usize,
+    /// A rule that every post has to be fully checked against, see Matcher::finish
+    unfiltered_rule: Option<String>,
+}
+
+/// The exclusion rules, checked on posts that would otherwise join the feed
+#[derive(Default)]
+pub struct Exclusions {
+    /// Names in file order
+    pub names: Vec<String>,
+    dids: HashMap<String, usize>,
+    domains: Vec<(String, usize)>,
+    matcher: Matcher,
+}
+
+impl Exclusions {
+    /// The name of the first exclusion that applies to a post by did with the
+    /// given match_fields. Authors are checked first, then link domains, then
+    /// expressions.
+    pub fn find(&self, did: &str, fields: &[(MatchSource, String)]) -> Option<&str> {
+        if let Some(&i) = self.dids.get(did) {
+            return Some(&self.names[i]);
+        }
+
+        let hosts: Vec<&str> = fields.iter()
+            .filter(|(source, _)| matches!(source, MatchSource::Link | MatchSource::CardUrl))
+            .filter_map(|(_, url)| host(url))
+            .collect();
+        for (domain, i) in &self.domains {
+            let hit = hosts.iter().any(|host| {
+                host.strip_suffix(domain.as_str()).is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+            });
+            if hit {
+                return Some(&self.names[*i]);
+            }
+        }
+
+        let mut candidates = Candidates::default();
+        for (_, text) in fields {
+            self.matcher.scan(text, &mut candidates);
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        let tokenized = tokenize(fields);
+        let rule = self.matcher.matches(&tokenized, &candidates).next().map(|m| m.rule);
+        rule
+    }
+}
+
+/// The host part of a lowercased URL, without any port
+fn host(url: &str) -> Option<&str> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = host.split(':').next()?;
+    (!host.is_empty()).then_some(host)
+}
+
+impl Rules {
+    pub fn parse(json: &str) -> Result<Rules, String> {
+        let file: RuleFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        file.validate()?;
+
+        let mut matcher = Matcher::default();
+        for keyword in &file.lax_keywords {
+            matcher.add_substring(keyword);
+        }
+        for keyword in &file.strict_keywords {
+            matcher.add_words(keyword)?;
+        }
+        for rule in &file.rules {
+            matcher.add_rule(&rule.name, &rule.expr)
+                .map_err(|e| format!("rule {:?}: {}", rule.name, e))?;
+        }
+        let gazetteer_names = match file.gazetteer {
+            Some(false) => 0,
+            _ => Gazetteer::builtin()?.add_to(&mut matcher, &file.strict_keywords)?,
+        };
+
+        let unfiltered_rule = matcher.finish().map(String::from);
+
+        let mut exclusions = Exclusions::default();
+        for (i, exclusion) in file.exclusions.iter().enumerate() {
+            exclusions.names.push(exclusion.name.clone());
+            for did in &exclusion.dids {
+                exclusions.dids.insert(did.clone(), i);
+            }
+            for domain in &exclusion.domains {
+                exclusions.domains.push((domain.to_lowercase(), i));
+            }
+            if let Some(expr) = &exclusion.expr {
+                exclusions.matcher.add_rule(&exclusion.name, expr)
+                    .map_err(|e| format!("exclusion {:?}: {}", exclusion.name, e))?;
+            }
+        }
+        exclusions.matcher.finish();
+
+        Ok(Rules {
+            lax_keywords: file.lax_keywords,
+            strict_keywords: file.strict_keywords,
+            nsfw_labels: file.nsfw_labels,
+            matcher,
+            exclusions,
+            rule_count: file.rules.len(),
+            gazetteer_names,
+            unfiltered_rule,
+        })
+    }
+
+    fn summary(&self) -> String {
+        let mut summary = format!("{} lax keywords, {} strict keywords, {} rules, {} gazetteer names, {} exclusions, {} NSFW labels",
+            self.lax_keywords.len(), self.strict_keywords.len(), self.rule_count, self.gazetteer_names,
+            self.exclusions.names.len(), self.nsfw_labels.len());
+        if let Some(rule) = &self.unfiltered_rule {
+            summary += &format!(". Rule {:?} has no plain text to look for, so every post gets the full match", rule);
+        }
+        summary
+    }
+}
+
+impl RuleFile {
+    fn validate(&self) -> Result<(), String> {
+        let names: Vec<String> = self.rules.iter().map(|rule| rule.name.clone()).collect();
+        let exclusion_names: Vec<String> = self.exclusions.iter().map(|exclusion| exclusion.name.clone()).collect();
+        let dids: Vec<String> = self.exclusions.iter().flat_map(|exclusion| exclusion.dids.clone()).collect();
+        let domains: Vec<String> = self.exclusions.iter()
+            .flat_map(|exclusion| exclusion.domains.iter().map(|domain| domain.to_lowercase()))
+            .collect();
+        let lists = [
+            ("lax_keywords", &self.lax_keywords),
+            ("strict_keywords", &self.strict_keywords),
+            ("rule names", &names),
+            ("exclusion names", &exclusion_names),
+            ("exclusion dids", &dids),
+            ("exclusion domains", &domains),
+            ("nsfw_labels", &self.nsfw_labels),
+        ];
+
+        for (name, values) in lists {
+            let mut seen = HashSet::new();
+            for value in values {
+                if value.trim().is_empty() {
+                    return Err(format!("{} has an empty entry", name));
+                }
+                if value.trim() != value {
+                    return Err(format!("{} entry {:?} has leading or trailing whitespace", name, value));
+                }
+                if !seen.insert(value) {
+                    return Err(format!("{} lists {:?} twice", name, value));
+                }
+            }
+        }
+
+        for exclusion in &self.exclusions {
+            if exclusion.expr.is_none() && exclusion.domains.is_empty() && exclusion.dids.is_empty() {
+                return Err(format!("exclusion {:?} has no match, domains or dids", exclusion.name));
+            }
+            if let Some(did) = exclusion.dids.iter().find(|did| !did.starts_with("did:")) {
+                return Err(format!("exclusion {:?} lists {:?}, which isn't a DID", exclusion.name, did));
+            }
+            if let Some(domain) = exclusion.domains.iter().find(|domain| domain.contains(['/', ':'])) {
+                return Err(format!("exclusion {:?} lists {:?}, domains are just the host name", exclusion.name, domain));
+            }
+        }
+
+        let no_gazetteer = self.gazetteer == Some(false);
+        if self.lax_keywords.is_empty() && self.strict_keywords.is_empty() && self.rules.is_empty() && no_gazetteer {
+            return Err("there are no keywords or rules and the gazetteer is off, nothing would ever match".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+fn rules() -> &'static RwLock<Arc<Rules>> {
+    RULES.get_or_init(|| {
+        let rules = Rules::parse(DEFAULT_RULES).expect("built-in rules.json is invalid");
+        RwLock::new(Arc::new(rules))
+    })
+}
+
+/// The rules in effect right now. Callers hold on to the Arc for as long as
+/// they need a consistent view, a reload only affects later calls.
+pub fn current() -> Arc<Rules> {
+    rules().read().unwrap().clone()
+}
+
+fn replace(new_rules: Rules) {
+    *rules().write().unwrap() = Arc::new(new_rules);
+}
+
+fn load_file(path: &str) -> Result<(Rules, Option<SystemTime>), String> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let json = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    let rules = Rules::parse(&json).map_err(|e| format!("invalid rules in {}: {}", path, e))?;
+    Ok((rules, modified))
+}
+
+/// Loads the rule file into rules if it changed since modified, or anyway if
+/// forced. Returns the new rules, or None if the file hasn't changed. If the
+/// file is invalid the rules already there are left alone.
+fn reload(path: &str, modified: &mut Option<SystemTime>, forced: bool, rules: &RwLock<Arc<Rules>>) -> Result<Option<Arc<Rules>>, String> {
+    let current = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    if !forced && (current.is_none() || current == *modified) {
+        return Ok(None);
+    }
+    // A bad file isn't retried until it changes again
+    *modified = current;
+
+    let (new_rules, _) = load_file(path)?;
+    let new_rules = Arc::new(new_rules);
+    *rules.write().unwrap() = new_rules.clone();
+    Ok(Some(new_rules))
+}
+
+/// Loads the rule file at TOSKY_RULES_PATH, or the built-in rules if it's not
+/// set, and reloads it when it changes or on SIGHUP. A bad file at startup is
+/// fatal; a bad edit later on is logged and the previous rules are kept.
+pub fn init_from_env() {
+    let path = match std::env::var("TOSKY_RULES_PATH") {
+        Ok(path) => path,
+        Err(_) => {
+            let rules = Rules::parse(DEFAULT_RULES).expect("built-in rules.json is invalid");
+            println!("[Rules] Using built-in rules: {}", rules.summary());
+            replace(rules);
+            return;
+        }
+    };
+
+    let mut modified = match load_file(&path) {
+        Ok((rules, modified)) => {
+            println!("[Rules] Loaded {}: {}", path, rules.summary());
+            replace(rules);
+            modified
+        },
+        Err(e) => {
+            eprintln!("[Rules] {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // SIGHUP wakes the watcher straight away, otherwise it checks the file's
+    // mtime every few seconds
+    let (hup_tx, hup_rx) = mpsc::channel();
+    match Signals::new([SIGHUP]) {
+        Ok(mut signals) => {
+            let hup_tx = hup_tx.clone();
+            thread::spawn(move || {
+                for _ in signals.forever() {
+                    hup_tx.send(()).ok();
+                }
+            });
+        },
+        Err(e) => eprintln!("[Rules] Could not listen for SIGHUP, only watching {} for changes: {}", path, e),
+    }
+
+    thread::spawn(move || loop {
+        // Holding a sender keeps recv_timeout waiting even without the signal thread
+        let _hup_tx = &hup_tx;
+        let forced = hup_rx.recv_timeout(Duration::from_secs(RELOAD_CHECK_SECS)).is_ok();
+        if forced {
+            println!("[Rules] SIGHUP, reloading {}", path);
+        }
+
+        match reload(&path, &mut modified, forced, rules()) {
+            Ok(Some(rules)) => println!("[Rules] Reloaded {}: {}", path, rules.summary()),
+            Ok(None) => {},
+            Err(e) => eprintln!("[Rules] Keeping the previous rules, {}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Rules with just the given exclusions, and a keyword so they're valid
+    fn with_exclusions(exclusions: serde_json::Value) -> Rules {
+        let json = json!({ "strict_keywords": ["ttc"], "exclusions": exclusions, "nsfw_labels": [], "gazetteer": false });
+        Rules::parse(&json.to_string()).unwrap()
+    }
+
+    fn fields(fields: &[(MatchSource, &str)]) -> Vec<(MatchSource, String)> {
+        fields.iter().map(|(source, text)| (*source, text.to_lowercase())).collect()
+    }
+
+    #[test]
+    fn domains_match_hosts_and_subdomains() {
+        let rules = with_exclusions(json!([{ "name": "aggregator", "domains": ["BlogTO.com"] }]));
+        for (source, url, excluded) in [
+            (MatchSource::Link, "https://blogto.com/eat", true),
+            (MatchSource::Link, "https://www.blogto.com/eat", true),
+            (MatchSource::CardUrl, "http://BLOGTO.COM:8080/x?y=1", true),
+            (MatchSource::Link, "https://someone@news.blogto.com", true),
+            (MatchSource::Link, "blogto.com/no-scheme", true),
+            (MatchSource::Link, "https://notblogto.com/eat", false),
+            (MatchSource::Link, "https://blogto.com.example.net/", false),
+            (MatchSource::Link, "https://example.com/?ref=blogto.com", false),
+            // Only links count, not a domain written out in the text
+            (MatchSource::Text, "https://blogto.com/eat", false),
+        ] {
+            let found = rules.exclusions.find("did:plc:someone", &fields(&[(source, url)]));
+            assert_eq!(found, excluded.then_some("aggregator"), "{:?} {:?}", source, url);
+        }
+    }
+
+    #[test]
+    fn dids_match_the_author() {
+        let rules = with_exclusions(json!([{ "name": "bots", "dids": ["did:plc:bot1", "did:web:bot2.example"] }]));
+        let post = fields(&[(MatchSource::Text, "ttc delays on line 1")]);
+        assert_eq!(rules.exclusions.find("did:plc:bot1", &post), Some("bots"));
+        assert_eq!(rules.exclusions.find("did:web:bot2.example", &post), Some("bots"));
+        assert_eq!(rules.exclusions.find("did:plc:person", &post), None);
+    }
+
+    #[test]
+    fn expressions_match_the_fields() {
+        let rules = with_exclusions(json!([
+            { "name": "crypto spam", "match": "airdrop OR memecoin*" },
+            { "name": "leafs fans elsewhere", "match": "leafs NEAR/2 fan* AND vancouver" },
+        ]));
+        for (post, excluded) in [
+            (vec![(MatchSource::Text, "TTC fares are going up, AIRDROP now")], Some("crypto spam")),
+            (vec![(MatchSource::Text, "ttc"), (MatchSource::Hashtag, "memecoins")], Some("crypto spam")),
+            (vec![(MatchSource::Text, "Leafs fans in Vancouver, meet at the ttc")], Some("leafs fans elsewhere")),
+            (vec![(MatchSource::Text, "Leafs fans on the ttc")], None),
+            (vec![(MatchSource::Text, "the ttc is running fine")], None),
+        ] {
+            assert_eq!(rules.exclusions.find("did:plc:someone", &fields(&post)), excluded, "{:?}", post);
+        }
+    }
+
+    #[test]
+    fn authors_then_domains_then_expressions() {
+        let rules = with_exclusions(json!([
+            { "name": "spam", "match": "airdrop" },
+            { "name": "aggregator", "domains": ["blogto.com"] },
+            { "name": "bots", "dids": ["did:plc:bot"] },
+        ]));
+        let post = fields(&[(MatchSource::Text, "airdrop"), (MatchSource::Link, "https://blogto.com")]);
+        assert_eq!(rules.exclusions.find("did:plc:bot", &post), Some("bots"));
+        assert_eq!(rules.exclusions.find("did:plc:someone", &post), Some("aggregator"));
+        assert_eq!(rules.exclusions.find("did:plc:someone", &post[..1]), Some("spam"));
+    }
+
+    #[test]
+    fn validation_errors() {
+        for (json, error) in [
+            (r#"{"strict_keywords": ["ttc"]}"#, "missing field `nsfw_labels`"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "keywords": []}"#, "unknown field `keywords`"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "rules": [{"name": "a", "match": "b", "weight": 2}]}"#, "unknown field `weight`"),
+            (r#"{"strict_keywords": ["ttc", ""], "nsfw_labels": []}"#, "strict_keywords has an empty entry"),
+            (r#"{"strict_keywords": ["ttc "], "nsfw_labels": []}"#, "strict_keywords entry \"ttc \" has leading or trailing whitespace"),
+            (r#"{"lax_keywords": ["toronto", "toronto"], "nsfw_labels": []}"#, "lax_keywords lists \"toronto\" twice"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": ["porn", "porn"]}"#, "nsfw_labels lists \"porn\" twice"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "rules": [{"name": "a", "match": "b"}, {"name": "a", "match": "c"}]}"#,
+                "rule names lists \"a\" twice"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "rules": [{"name": "a", "match": "(b"}]}"#, "rule \"a\": missing ')'"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "rules": [{"name": "a", "match": "NOT b"}]}"#,
+                "rule \"a\": it only says what not to match"),
+            (r#"{"strict_keywords": ["*"], "nsfw_labels": []}"#, "keyword \"*\": "),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "exclusions": [{"name": "x"}]}"#,
+                "exclusion \"x\" has no match, domains or dids"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "exclusions": [{"name": "x", "dids": ["plc:abc"]}]}"#,
+                "exclusion \"x\" lists \"plc:abc\", which isn't a DID"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "exclusions": [{"name": "x", "domains": ["https://blogto.com"]}]}"#,
+                "exclusion \"x\" lists \"https://blogto.com\", domains are just the host name"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "exclusions": [{"name": "x", "domains": ["a.com"]}, {"name": "y", "domains": ["A.com"]}]}"#,
+                "exclusion domains lists \"a.com\" twice"),
+            (r#"{"strict_keywords": ["ttc"], "nsfw_labels": [], "exclusions": [{"name": "x", "match": "NOT b"}]}"#,
+                "exclusion \"x\": it only says what not to match"),
+            (r#"{"nsfw_labels": [], "gazetteer": false}"#, "there are no keywords or rules and the gazetteer is off"),
+        ] {
+            let result = Rules::parse(json).err();
+            assert!(result.as_deref().is_some_and(|e| e.starts_with(error)), "{} gave {:?}, expected {:?}", json, result, error);
+        }
+
+        // No keywords of its own is fine while the gazetteer is on
+        assert!(Rules::parse(r#"{"nsfw_labels": []}"#).is_ok());
+        assert!(Rules::parse(DEFAULT_RULES).is_ok());
+    }
+
+    #[test]
+    fn reload_keeps_the_previous_rules_if_the_file_is_invalid() {
+        let path = std::env::temp_dir().join(format!("tosky-rules-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let write = |keywords: &str| {
+            std::fs::write(path, format!(r#"{{"strict_keywords": {}, "nsfw_labels": [], "gazetteer": false}}"#, keywords)).unwrap();
+        };
+        let keywords = |rules: &RwLock<Arc<Rules>>| rules.read().unwrap().strict_keywords.clone();
+
+        write(r#"["ttc"]"#);
+        let (loaded, mut modified) = load_file(path).unwrap();
+        let rules = RwLock::new(Arc::new(loaded));
+
+        // Unchanged, so only a forced reload (SIGHUP) reads it again
+        assert!(reload(path, &mut modified, false, &rules).unwrap().is_none());
+        assert!(reload(path, &mut modified, true, &rules).unwrap().is_some());
+
+        write(r#"["ttc", "ttc"]"#);
+        let error = reload(path, &mut modified, true, &rules).err().unwrap();
+        assert!(error.contains("strict_keywords lists \"ttc\" twice"), "{}", error);
+        assert_eq!(keywords(&rules), ["ttc"]);
+
+        write(r#"["ttc", "yyz"]"#);
+        assert!(reload(path, &mut modified, true, &rules).unwrap().is_some());
+        assert_eq!(keywords(&rules), ["ttc", "yyz"]);
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -1,9 +1,18 @@
 use tiny_http::{Server, Response};
 use std::sync::Arc;
 use crate::db::Database;
-use std::thread;
+use crate::rules;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use urlencoding::decode;
 
+/// TOSKY_COLLAPSE_THREADS=1 shows a thread once, as its topmost post in the feed
+fn collapse_threads() -> bool {
+    std::env::var("TOSKY_COLLAPSE_THREADS")
+        .map(|v| v == "1" || v == "true")
+        .unwrap_or(false)
+}
+
 fn hostname() -> String {
     std::env::var("TOSKY_HOSTNAME")
         .unwrap_or_else(|_| "unobscenely-keyed-tatiana.ngrok-free.dev".to_string())
@@ -46,93 +55,179 @@ pub fn start_server(db_path: &str) {
     println!("Server running on http://localhost:{}", port);
 
     let num_guards = 4;
-    let mut guards = Vec::with_capacity(num_guards);
+    let mut guards = Vec::with_capacity(num_guards + 1);
 
-    let db_path = db_path.to_string();
+    let collapse_threads = collapse_threads();
     for _ in 0..num_guards {
-        let server = server.clone();
-        let db_path = db_path.clone();
-
-        let guard = thread::spawn(move || {
-            let db = Database::new(&db_path);
-
-            loop {
-                match server.recv() {
-                    Ok(rq) => {
-                        let url = rq.url();
-
-                        if url == "/.well-known/did.json" {
-                            let response = Response::from_string(did_json())
-                                .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
-                            rq.respond(response).ok();
-                        } else if url.starts_with("/xrpc/app.bsky.feed.describeFeedGenerator") {
-                            let response = Response::from_string(describe_feed_generator())
-                                .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
-                            rq.respond(response).ok();
-                        } else if url.starts_with("/xrpc/app.bsky.feed.getFeedSkeleton") {
-                            let params = parse_query_params(url);
-
-                            // Parse limit (default 50, max 100)
-                            let limit: i64 = params.get("limit")
-                                .and_then(|s| s.parse().ok())
-                                .unwrap_or(50)
-                                .min(100);
-
-                            // Parse cursor (format: "timestamp:seed" or none)
-                            let (cursor, seed): (Option<i64>, u32) = match params.get("cursor") {
-                                Some(c) => {
-                                    let decoded = decode(c).unwrap_or(std::borrow::Cow::Borrowed(c));
-                                    println!("[Server] Raw cursor: {}, decoded: {}", c, decoded);
-                                    let parts: Vec<&str> = decoded.split(':').collect();
-                                    let ts = parts.get(0).and_then(|x| x.parse().ok());
-                                    let s = parts.get(1).and_then(|x| x.parse().ok()).unwrap_or_else(|| rand::random::<u32>());
-
-                                    (ts, s)
-                                },
-                                None => (None, rand::random::<u32>())
-                            };
-
-                            println!("[Server] getFeedSkeleton request - limit:{} cursor:{:?}", limit, cursor);
-
-                            let (posts, next_cursor) = db.read_posts(limit, cursor, seed);
-
-                            println!("[Server] Returning {} posts, next_cursor:{:?}", posts.len(), next_cursor);
-                            for (i, uri) in posts.iter().enumerate() {
-                                println!("[Server]   {}. {}", i + 1, uri);
-                            }
-
-                            // Build feed array
-                            let feed: Vec<String> = posts
-                                .iter()
-                                .map(|uri| format!(r#"{{"post":"{}"}}"#, uri))
-                                .collect();
-
-                            // Build response with optional cursor
-                            let json = match next_cursor {
-                                Some(c) => {
-                                    let cursor_str = format!("{}:{}", c, seed);
-                                    println!("[Server] Returning cursor: {}", cursor_str);
-                                    format!(r#"{{"feed":[{}],"cursor":"{}"}}"#, feed.join(","), cursor_str)
-                                },
-                                None => format!(r#"{{"feed":[{}]}}"#, feed.join(",")),
-                            };
-
-                            let response = Response::from_string(json)
-                                .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
-                            rq.respond(response).ok();
-                        } else {
-                            let response = Response::from_string("Not Found").with_status_code(404);
-                            rq.respond(response).ok();
+        guards.push((server.clone(), false, serve(server.clone(), db_path, collapse_threads, false)));
+    }
+
+    // The admin routes show who engaged with what and how the rules are
+    // doing, so they're only served on a separate loopback listener
+    let admin_port = std::env::var("TOSKY_ADMIN_PORT").unwrap_or_else(|_| "3001".to_string());
+    match Server::http(format!("127.0.0.1:{}", admin_port)) {
+        Ok(admin) => {
+            println!("Admin endpoints on http://127.0.0.1:{}/admin/", admin_port);
+            let admin = Arc::new(admin);
+            guards.push((admin.clone(), true, serve(admin, db_path, collapse_threads, true)));
+        },
+        Err(e) => eprintln!("Could not start the admin listener on port {}: {}", admin_port, e),
+    }
+
+    // Workers stop if they panic or their listener errors. Each one is joined
+    // to log a panic and replaced, so neither listener runs out of threads.
+    loop {
+        thread::sleep(Duration::from_secs(1));
+
+        for (server, admin, guard) in guards.iter_mut() {
+            if !guard.is_finished() {
+                continue;
+            }
+
+            let worker = std::mem::replace(guard, serve(server.clone(), db_path, collapse_threads, *admin));
+            if let Err(panic) = worker.join() {
+                let message = panic.downcast_ref::<&str>().copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                eprintln!("[Server] {} worker panicked, restarting it: {}", if *admin { "Admin" } else { "Feed" }, message);
+            }
+        }
+    }
+}
+
+/// Answers requests on `server` from a worker thread. The /admin routes are
+/// only answered when `admin` is set, everywhere else they're a 404.
+fn serve(server: Arc<Server>, db_path: &str, collapse_threads: bool, admin: bool) -> JoinHandle<()> {
+    let db_path = db_path.to_string();
+
+    thread::spawn(move || {
+        let db = Database::new(&db_path);
+
+        loop {
+            match server.recv() {
+                Ok(rq) => {
+                    let url = rq.url();
+
+                    if url == "/.well-known/did.json" {
+                        let response = Response::from_string(did_json())
+                            .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
+                        rq.respond(response).ok();
+                    } else if url.starts_with("/xrpc/app.bsky.feed.describeFeedGenerator") {
+                        let response = Response::from_string(describe_feed_generator())
+                            .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
+                        rq.respond(response).ok();
+                    } else if url.starts_with("/xrpc/app.bsky.feed.getFeedSkeleton") {
+                        let params = parse_query_params(url);
+
+                        // Parse limit (default 50, max 100)
+                        let limit: i64 = params.get("limit")
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(50)
+                            .min(100);
+
+                        // Parse cursor (format: "timestamp:seed" or none)
+                        let (cursor, seed): (Option<i64>, u32) = match params.get("cursor") {
+                            Some(c) => {
+                                let decoded = decode(c).unwrap_or(std::borrow::Cow::Borrowed(c));
+                                println!("[Server] Raw cursor: {}, decoded: {}", c, decoded);
+                                let parts: Vec<&str> = decoded.split(':').collect();
+                                let ts = parts.get(0).and_then(|x| x.parse().ok());
+                                let s = parts.get(1).and_then(|x| x.parse().ok()).unwrap_or_else(|| rand::random::<u32>());
+
+                                (ts, s)
+                            },
+                            None => (None, rand::random::<u32>())
+                        };
+
+                        println!("[Server] getFeedSkeleton request - limit:{} cursor:{:?}", limit, cursor);
+
+                        let (posts, next_cursor) = db.read_posts(limit, cursor, seed, collapse_threads);
+
+                        println!("[Server] Returning {} posts, next_cursor:{:?}", posts.len(), next_cursor);
+                        for (i, uri) in posts.iter().enumerate() {
+                            println!("[Server]   {}. {}", i + 1, uri);
                         }
-                    },
-                    Err(e) => {
-                        eprintln!("Server error: {}", e);
-                        break
+
+                        // Build feed array
+                        let feed: Vec<String> = posts
+                            .iter()
+                            .map(|uri| format!(r#"{{"post":"{}"}}"#, uri))
+                            .collect();
+
+                        // Build response with optional cursor
+                        let json = match next_cursor {
+                            Some(c) => {
+                                let cursor_str = format!("{}:{}", c, seed);
+                                println!("[Server] Returning cursor: {}", cursor_str);
+                                format!(r#"{{"feed":[{}],"cursor":"{}"}}"#, feed.join(","), cursor_str)
+                            },
+                            None => format!(r#"{{"feed":[{}]}}"#, feed.join(",")),
+                        };
+
+                        let response = Response::from_string(json)
+                            .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
+                        rq.respond(response).ok();
+                    } else if admin && url.starts_with("/admin/engagers") {
+                        let params = parse_query_params(url);
+                        let uri = params.get("uri")
+                            .map(|u| decode(u).map(|d| d.into_owned()).unwrap_or_else(|_| u.clone()))
+                            .unwrap_or_default();
+
+                        let engagers: Vec<String> = db.get_engagers(&uri)
+                            .iter()
+                            .map(|(did, kind)| format!(r#"{{"did":"{}","kind":"{}"}}"#, did, kind))
+                            .collect();
+
+                        let json = format!(r#"{{"uri":{},"engagers":[{}]}}"#, serde_json::Value::from(uri), engagers.join(","));
+                        let response = Response::from_string(json)
+                            .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
+                        rq.respond(response).ok();
+                    } else if admin && url.starts_with("/admin/why") {
+                        // uri can be an at:// URI or a bsky.app post link
+                        let params = parse_query_params(url);
+                        let link = params.get("uri")
+                            .map(|u| decode(u).map(|d| d.into_owned()).unwrap_or_else(|_| u.clone()))
+                            .unwrap_or_default();
+                        let uri = db.post_uri(&link).unwrap_or(link);
+
+                        let json = match db.get_match_reason(&uri) {
+                            Some(reason) => format!(r#"{{"uri":{},"in_feed":true,"rules":{},"field":{},"snippet":{}}}"#,
+                                serde_json::Value::from(uri), serde_json::Value::from(reason.rules),
+                                if reason.field.is_empty() { serde_json::Value::Null } else { serde_json::Value::from(reason.field) },
+                                serde_json::Value::from(reason.snippet)),
+                            None => format!(r#"{{"uri":{},"in_feed":false}}"#, serde_json::Value::from(uri)),
+                        };
+                        let response = Response::from_string(json)
+                            .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
+                        rq.respond(response).ok();
+                    } else if admin && url.starts_with("/admin/exclusions") {
+                        // Every exclusion in the current rules, including ones that
+                        // haven't suppressed anything yet
+                        let mut suppressions = db.get_suppressions();
+                        let exclusions: Vec<String> = rules::current().exclusions.names
+                            .iter()
+                            .map(|name| {
+                                let (count, last_uri) = suppressions.remove(name).unwrap_or_default();
+                                format!(r#"{{"name":{},"suppressed":{},"last_uri":{}}}"#,
+                                    serde_json::Value::from(name.as_str()), count,
+                                    if last_uri.is_empty() { serde_json::Value::Null } else { serde_json::Value::from(last_uri) })
+                            })
+                            .collect();
+
+                        let json = format!(r#"{{"exclusions":[{}]}}"#, exclusions.join(","));
+                        let response = Response::from_string(json)
+                            .with_header("Content-Type: application/json".parse::<tiny_http::Header>().unwrap());
+                        rq.respond(response).ok();
+                    } else {
+                        let response = Response::from_string("Not Found").with_status_code(404);
+                        rq.respond(response).ok();
                     }
+                },
+                Err(e) => {
+                    eprintln!("Server error: {}", e);
+                    break
                 }
             }
-        });
-
-        guards.push(guard);
-    }
+        }
+    })
 }
\ No newline at end of file
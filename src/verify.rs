@@ -0,0 +1,591 @@
+use crate::did::{resolver_from_env, DidResolver, SigningKey};
+use crate::models::{Action, Cid, Commit, Frame, MstNode};
+use crate::parser::{cbor_item_len, read_cbor_head, read_varint};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CODEC_DAG_CBOR: u64 = 0x71;
+const HASH_SHA2_256: u64 = 0x12;
+// How long a DID that couldn't be resolved, or whose freshly resolved key
+// still didn't verify, goes without another lookup
+const FAILED_LOOKUP_SECS: u64 = 300;
+const FAILED_LOOKUP_CACHE: usize = 10_000;
+
+/// Everything decode_frame checks before trusting a frame's contents. Shared
+/// by the decode workers, so all of it works through &self.
+pub struct Verifier {
+    pub blocks: BlockVerifier,
+    pub commits: CommitVerifier,
+}
+
+impl Verifier {
+    pub fn from_env() -> Self {
+        let commits = CommitVerifier::from_env();
+        // A signed commit vouches for record CIDs, not bytes, so checking
+        // commits only means something if the blocks are checked too
+        let blocks = BlockVerifier::new(env_flag("TOSKY_VERIFY_BLOCKS") || commits.enabled());
+        Verifier { blocks, commits }
+    }
+
+    /// Checks blocks or not, and never commits, which need DID documents.
+    /// For benchmarks.
+    pub fn new(verify_blocks: bool) -> Self {
+        Verifier { blocks: BlockVerifier::new(verify_blocks), commits: CommitVerifier::new(CommitMode::Off, resolver_from_env()) }
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| v == "1" || v == "true")
+        .unwrap_or(false)
+}
+
+/// Optionally checks that each record block really hashes to the CID the
+/// commit op points at, so a buggy or malicious relay can't hand us altered
+/// records. Off by default since bsky.network is trusted; set
+/// TOSKY_VERIFY_BLOCKS=1 when running against third-party or self-hosted relays.
+pub struct BlockVerifier {
+    enabled: bool,
+    pub checked: AtomicU64,
+    pub mismatches: AtomicU64,
+}
+
+impl BlockVerifier {
+    fn new(enabled: bool) -> Self {
+        if enabled {
+            println!("[Ingestion] Verifying record blocks against their CIDs");
+        }
+
+        BlockVerifier { enabled, checked: AtomicU64::new(0), mismatches: AtomicU64::new(0) }
+    }
+
+    /// True if the block can be trusted (or verification is off). Failures
+    /// are counted and logged, and the caller should drop the record.
+    pub fn check(&self, cid: &Cid, data: &[u8]) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        match verify_block(cid, data) {
+            Ok(()) => true,
+            Err(reason) => {
+                let mismatches = self.mismatches.fetch_add(1, Ordering::Relaxed) + 1;
+                eprintln!("[Ingestion] Block {} failed verification ({} so far): {}", cid, mismatches, reason);
+                false
+            }
+        }
+    }
+}
+
+/// Records must be dag-cbor blocks addressed by a sha2-256 multihash of their bytes
+fn verify_block(cid: &Cid, data: &[u8]) -> Result<(), String> {
+    // [version][codec][hash type][hash len][digest]
+    let bytes = cid.as_bytes();
+    let mut pos = 0;
+    let mut next = || -> Result<u64, String> {
+        let (value, size) = read_varint(&bytes[pos..]).map_err(|e| e.to_string())?;
+        pos += size;
+        Ok(value)
+    };
+
+    let _version = next()?;
+    let codec = next()?;
+    let hash_type = next()?;
+    let hash_len = next()?;
+
+    if codec != CODEC_DAG_CBOR {
+        return Err(format!("codec 0x{:x} is not dag-cbor", codec));
+    }
+    if hash_type != HASH_SHA2_256 || hash_len != 32 {
+        return Err(format!("unsupported multihash 0x{:x} ({} bytes)", hash_type, hash_len));
+    }
+
+    let digest = &bytes[pos..];
+    if digest != Sha256::digest(data).as_slice() {
+        return Err("sha-256 digest doesn't match block data".to_string());
+    }
+
+    Ok(())
+}
+
+#[derive(PartialEq)]
+enum CommitMode {
+    Off,
+    /// Verify and log failures, but still ingest
+    Log,
+    /// Drop commits that can't be verified
+    Drop,
+}
+
+/// Checks that each #commit is signed by the repo's current signing key and
+/// that every op's path really maps to its CID in the commit's MST. Meant for
+/// relays we don't run or trust; TOSKY_VERIFY_COMMITS=log or =drop turns it on.
+pub struct CommitVerifier {
+    mode: CommitMode,
+    resolver: Box<dyn DidResolver>,
+    keys: Mutex<LruCache<String, SigningKey>>,
+    /// DIDs whose last lookup didn't help, and when. Keeps a flood of bad
+    /// commits from tying every decode worker up in curl.
+    failed_lookups: Mutex<LruCache<String, Instant>>,
+    pub verified: AtomicU64,
+    pub failures: AtomicU64,
+}
+
+impl CommitVerifier {
+    pub fn from_env() -> Self {
+        let mode = match std::env::var("TOSKY_VERIFY_COMMITS").as_deref() {
+            Ok("log") => CommitMode::Log,
+            Ok("drop") => CommitMode::Drop,
+            _ => CommitMode::Off,
+        };
+
+        if mode != CommitMode::Off {
+            println!("[Ingestion] Verifying commit signatures and MST paths ({})",
+                if mode == CommitMode::Drop { "dropping failures" } else { "logging failures" });
+        }
+
+        CommitVerifier::new(mode, resolver_from_env())
+    }
+
+    fn new(mode: CommitMode, resolver: Box<dyn DidResolver>) -> Self {
+        CommitVerifier {
+            mode,
+            resolver,
+            keys: Mutex::new(LruCache::new(NonZeroUsize::new(100_000).unwrap())),
+            failed_lookups: Mutex::new(LruCache::new(NonZeroUsize::new(FAILED_LOOKUP_CACHE).unwrap())),
+            verified: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.mode != CommitMode::Off
+    }
+
+    /// An #identity event means the signing key may have rotated. Called from
+    /// the ordered writer stage, not the workers, which see frames out of order.
+    pub fn forget(&self, did: &str) {
+        self.keys.lock().unwrap().pop(did);
+        self.failed_lookups.lock().unwrap().pop(did);
+    }
+
+    /// False if the commit failed verification and should be dropped
+    pub fn check(&self, frame: &Frame, blocks: &HashMap<&[u8], &[u8]>) -> bool {
+        if !self.enabled() {
+            return true;
+        }
+
+        match self.verify(frame, blocks) {
+            Ok(()) => {
+                self.verified.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            Err(reason) => {
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                eprintln!("[Ingestion] Commit {} by {} failed verification ({} so far): {}",
+                    frame.seq, frame.repo, failures, reason);
+                self.mode != CommitMode::Drop
+            }
+        }
+    }
+
+    fn verify(&self, frame: &Frame, blocks: &HashMap<&[u8], &[u8]>) -> Result<(), String> {
+        let link = frame.commit.as_deref().ok_or("frame has no commit link")?;
+        let commit_cid = Cid::from_tag42(link).map_err(|e| e.to_string())?;
+        let commit_block = load_block(blocks, &commit_cid)?;
+        let commit: Commit = serde_cbor::from_slice(commit_block).map_err(|e| format!("bad commit object: {}", e))?;
+
+        if commit.did != frame.repo {
+            return Err(format!("commit is for {}, not {}", commit.did, frame.repo));
+        }
+        if !matches!(commit.version, 2 | 3) {
+            return Err(format!("unsupported commit version {}", commit.version));
+        }
+
+        let unsigned = unsigned_commit(commit_block)?;
+        self.check_signature(&commit.did, &unsigned, &commit.sig)?;
+
+        let root = Cid::from_tag42(&commit.data).map_err(|e| e.to_string())?;
+        for op in &frame.ops {
+            let found = mst_lookup(blocks, &root, op.path.as_bytes())?;
+            match (&op.action, found, op.cid.as_deref()) {
+                (Action::Delete, None, _) => {},
+                (Action::Create | Action::Update, Some(found), Some(expected)) if found == expected => {},
+                (Action::Delete, Some(_), _) => return Err(format!("deleted {} is still in the MST", op.path)),
+                _ => return Err(format!("{} doesn't match the MST", op.path)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_signature(&self, did: &str, message: &[u8], signature: &[u8]) -> Result<(), String> {
+        if self.keys.lock().unwrap().get(did).is_some_and(|key| key.verify(message, signature)) {
+            return Ok(());
+        }
+
+        let recently_failed = self.failed_lookups.lock().unwrap().get(did)
+            .is_some_and(|at| at.elapsed() < Duration::from_secs(FAILED_LOOKUP_SECS));
+        if recently_failed {
+            return Err("signature doesn't match, and looking the DID up again recently didn't help".to_string());
+        }
+
+        // Not cached yet, or the key rotated since we cached it. Resolved
+        // without holding the lock so other workers aren't stuck behind curl.
+        let result = self.resolver.resolve(did)
+            .and_then(|document| SigningKey::from_did_document(&document, did))
+            .and_then(|key| {
+                let valid = key.verify(message, signature);
+                self.keys.lock().unwrap().put(did.to_string(), key);
+                if valid {
+                    Ok(())
+                } else {
+                    Err("signature doesn't match the repo's signing key".to_string())
+                }
+            });
+
+        let mut failed_lookups = self.failed_lookups.lock().unwrap();
+        match result {
+            Ok(()) => failed_lookups.pop(did),
+            Err(_) => failed_lookups.put(did.to_string(), Instant::now()),
+        };
+        result
+    }
+}
+
+/// A block from the frame's CAR, checked against its CID
+fn load_block<'a>(blocks: &HashMap<&[u8], &'a [u8]>, cid: &Cid) -> Result<&'a [u8], String> {
+    let data = blocks.get(cid.as_bytes()).ok_or_else(|| format!("block {} is missing from the frame", cid))?;
+    verify_block(cid, data).map_err(|e| format!("block {}: {}", cid, e))?;
+    Ok(data)
+}
+
+/// The bytes that were signed: the commit's DAG-CBOR encoding without its
+/// "sig" entry. DAG-CBOR keys are sorted, so dropping the entry in place
+/// leaves a canonical encoding.
+fn unsigned_commit(block: &[u8]) -> Result<Vec<u8>, String> {
+    const SIG_KEY: &[u8] = &[0x63, b's', b'i', b'g']; // text(3) "sig"
+
+    let (major, entries, mut pos) = read_cbor_head(block).map_err(|e| e.to_string())?;
+    if major != 5 || entries == 0 {
+        return Err("commit object is not a map".to_string());
+    }
+
+    let mut out = cbor_head(5, entries - 1);
+    let mut found = false;
+    for _ in 0..entries {
+        let key_len = cbor_item_len(&block[pos..]).map_err(|e| e.to_string())?;
+        let value_len = cbor_item_len(&block[pos + key_len..]).map_err(|e| e.to_string())?;
+
+        if &block[pos..pos + key_len] == SIG_KEY {
+            found = true;
+        } else {
+            out.extend_from_slice(&block[pos..pos + key_len + value_len]);
+        }
+        pos += key_len + value_len;
+    }
+
+    if !found {
+        return Err("commit is unsigned".to_string());
+    }
+    Ok(out)
+}
+
+fn cbor_head(major: u8, n: u64) -> Vec<u8> {
+    let major = major << 5;
+    match n {
+        0..=23 => vec![major | n as u8],
+        24..=0xff => vec![major | 24, n as u8],
+        0x100..=0xffff => [&[major | 25][..], &(n as u16).to_be_bytes()].concat(),
+        0x1_0000..=0xffff_ffff => [&[major | 26][..], &(n as u32).to_be_bytes()].concat(),
+        _ => [&[major | 27][..], &n.to_be_bytes()].concat(),
+    }
+}
+
+/// Walks the MST from root to the node that would hold key, returning the
+/// value CID (tag 42 form) if the key is present. Every node on the way has
+/// to be in the frame, otherwise the op can't be proven either way.
+fn mst_lookup(blocks: &HashMap<&[u8], &[u8]>, root: &Cid, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let mut node_cid = root.clone();
+
+    loop {
+        let data = load_block(blocks, &node_cid)?;
+        let node: MstNode = serde_cbor::from_slice(data).map_err(|e| format!("bad MST node {}: {}", node_cid, e))?;
+
+        // Entries are sorted; keys before an entry live in the subtree to its left
+        let mut subtree = node.l.as_deref();
+        let mut entry_key: Vec<u8> = Vec::new();
+        for entry in &node.e {
+            if entry.p > entry_key.len() {
+                return Err(format!("bad key prefix in MST node {}", node_cid));
+            }
+            entry_key.truncate(entry.p);
+            entry_key.extend_from_slice(&entry.k);
+
+            match entry_key.as_slice().cmp(key) {
+                std::cmp::Ordering::Equal => return Ok(Some(entry.v.clone())),
+                std::cmp::Ordering::Greater => break,
+                std::cmp::Ordering::Less => subtree = entry.t.as_deref(),
+            }
+        }
+
+        match subtree {
+            Some(link) => node_cid = Cid::from_tag42(link).map_err(|e| e.to_string())?,
+            None => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::did::FixtureResolver;
+    use crate::models::Operation;
+    use k256::ecdsa::signature::Signer;
+    use serde_json::{json, Value};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    const DID: &str = "did:plc:testrepo";
+    const PATH: &str = "app.bsky.feed.post/3kabc";
+    const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+    /// Deterministic secp256k1 keys, so the tests don't need a RNG
+    fn secret(seed: u8) -> k256::ecdsa::SigningKey {
+        k256::ecdsa::SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn encode_base58(bytes: &[u8]) -> String {
+        let mut digits: Vec<u8> = Vec::new();
+        for &byte in bytes {
+            let mut carry = byte as u32;
+            for digit in digits.iter_mut() {
+                carry += (*digit as u32) << 8;
+                *digit = (carry % 58) as u8;
+                carry /= 58;
+            }
+            while carry > 0 {
+                digits.push((carry % 58) as u8);
+                carry /= 58;
+            }
+        }
+        let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+        std::iter::repeat_n('1', zeros)
+            .chain(digits.iter().rev().map(|&d| BASE58_ALPHABET[d as usize] as char))
+            .collect()
+    }
+
+    fn did_document(key: &k256::ecdsa::SigningKey) -> Value {
+        let public = key.verifying_key().to_encoded_point(true);
+        let multikey = [&[0xe7, 0x01][..], public.as_bytes()].concat();
+        json!({
+            "id": DID,
+            "verificationMethod": [{
+                "id": format!("{}#atproto", DID),
+                "type": "Multikey",
+                "controller": DID,
+                "publicKeyMultibase": format!("z{}", encode_base58(&multikey)),
+            }],
+        })
+    }
+
+    /// A fixture file holding document, opened the way TOSKY_DID_FIXTURES is
+    fn fixture_resolver(name: &str, document: Value) -> FixtureResolver {
+        let path = std::env::temp_dir().join(format!("tosky-did-fixture-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, json!({ DID: document }).to_string()).unwrap();
+        let resolver = FixtureResolver::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).ok();
+        resolver
+    }
+
+    /// Serves whatever document is current and counts lookups
+    struct Swappable {
+        document: Arc<Mutex<Value>>,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    impl DidResolver for Swappable {
+        fn resolve(&self, _did: &str) -> Result<Value, String> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            Ok(self.document.lock().unwrap().clone())
+        }
+    }
+
+    fn text(s: &str) -> Vec<u8> {
+        [&[0x60 | s.len() as u8][..], s.as_bytes()].concat()
+    }
+
+    fn bytes(b: &[u8]) -> Vec<u8> {
+        [&[0x58, b.len() as u8][..], b].concat()
+    }
+
+    fn link(cid: &Cid) -> Vec<u8> {
+        [&[0xd8, 0x2a][..], &bytes(&[&[0x00][..], cid.as_bytes()].concat())].concat()
+    }
+
+    fn tag42(cid: &Cid) -> Vec<u8> {
+        [&[0x00][..], cid.as_bytes()].concat()
+    }
+
+    fn cid_of(block: &[u8]) -> Cid {
+        Cid::from_bytes(&[&[0x01, 0x71, 0x12, 0x20][..], &Sha256::digest(block)].concat()).unwrap()
+    }
+
+    /// The blocks of a one record repo, with a commit signed by key
+    struct Repo {
+        blocks: Vec<(Cid, Vec<u8>)>,
+        commit: Cid,
+        record: Cid,
+    }
+
+    impl Repo {
+        fn new(key: &k256::ecdsa::SigningKey) -> Self {
+            let record = b"\xa1\x64text\x65hello".to_vec();
+            let record_cid = cid_of(&record);
+
+            // {"e": [{"k": PATH, "p": 0, "t": null, "v": record}], "l": null}
+            let node = [
+                &[0xa2][..], &text("e"), &[0x81, 0xa4], &text("k"), &bytes(PATH.as_bytes()), &text("p"), &[0x00],
+                &text("t"), &[0xf6], &text("v"), &link(&record_cid), &text("l"), &[0xf6],
+            ].concat();
+            let node_cid = cid_of(&node);
+
+            // DAG-CBOR sorts keys by length, then bytes: did, sig, data, version
+            let did = [&text("did")[..], &text(DID)].concat();
+            let data = [&text("data")[..], &link(&node_cid)].concat();
+            let version = [&text("version")[..], &[0x03]].concat();
+            let unsigned = [&[0xa3][..], &did, &data, &version].concat();
+            let signature: k256::ecdsa::Signature = key.sign(&unsigned);
+            let commit = [&[0xa4][..], &did, &text("sig"), &bytes(&signature.to_bytes()), &data, &version].concat();
+            let commit_cid = cid_of(&commit);
+
+            Repo {
+                blocks: vec![(record_cid.clone(), record), (node_cid, node), (commit_cid.clone(), commit)],
+                commit: commit_cid,
+                record: record_cid,
+            }
+        }
+
+        fn frame(&self, path: &str) -> Frame {
+            Frame {
+                repo: DID.to_string(),
+                ops: vec![Operation {
+                    action: Action::Create,
+                    path: path.to_string(),
+                    cid: Some(tag42(&self.record)),
+                    prev: None,
+                }],
+                blocks: Vec::new(),
+                commit: Some(tag42(&self.commit)),
+                seq: 1,
+                time: String::new(),
+            }
+        }
+
+        fn blocks(&self) -> HashMap<&[u8], &[u8]> {
+            self.blocks.iter().map(|(cid, data)| (cid.as_bytes(), data.as_slice())).collect()
+        }
+    }
+
+    fn verifier(resolver: impl DidResolver + 'static) -> CommitVerifier {
+        CommitVerifier::new(CommitMode::Drop, Box::new(resolver))
+    }
+
+    #[test]
+    fn valid_signature() {
+        let key = secret(1);
+        let verifier = verifier(fixture_resolver("valid", did_document(&key)));
+        let repo = Repo::new(&key);
+
+        assert_eq!(verifier.verify(&repo.frame(PATH), &repo.blocks()), Ok(()));
+        assert!(verifier.check(&repo.frame(PATH), &repo.blocks()));
+    }
+
+    #[test]
+    fn bad_signature() {
+        let verifier = verifier(fixture_resolver("bad", did_document(&secret(1))));
+        let repo = Repo::new(&secret(2));
+
+        let error = verifier.verify(&repo.frame(PATH), &repo.blocks()).unwrap_err();
+        assert!(error.contains("signature"), "{}", error);
+        assert!(!verifier.check(&repo.frame(PATH), &repo.blocks()));
+    }
+
+    #[test]
+    fn tampered_commit() {
+        let key = secret(1);
+        let verifier = verifier(fixture_resolver("tampered", did_document(&key)));
+        let mut repo = Repo::new(&key);
+
+        // Altered after signing, and re-addressed so the block itself checks out
+        let (_, commit) = repo.blocks.pop().unwrap();
+        let tampered = [&commit[..commit.len() - 1], &[0x02]].concat();
+        repo.commit = cid_of(&tampered);
+        repo.blocks.push((repo.commit.clone(), tampered));
+
+        assert!(verifier.verify(&repo.frame(PATH), &repo.blocks()).is_err());
+    }
+
+    #[test]
+    fn rotated_key() {
+        let (old, new) = (secret(1), secret(2));
+        let document = Arc::new(Mutex::new(did_document(&old)));
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let verifier = verifier(Swappable { document: document.clone(), lookups: lookups.clone() });
+
+        let before = Repo::new(&old);
+        assert_eq!(verifier.verify(&before.frame(PATH), &before.blocks()), Ok(()));
+        assert_eq!(verifier.verify(&before.frame(PATH), &before.blocks()), Ok(()));
+        assert_eq!(lookups.load(Ordering::Relaxed), 1, "the key should be cached");
+
+        // The cached key fails on the new signature, so the DID is looked up again
+        *document.lock().unwrap() = did_document(&new);
+        let after = Repo::new(&new);
+        assert_eq!(verifier.verify(&after.frame(PATH), &after.blocks()), Ok(()));
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+
+        // And the old key no longer counts
+        assert!(verifier.verify(&before.frame(PATH), &before.blocks()).is_err());
+    }
+
+    #[test]
+    fn failed_lookups_are_cached() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = Swappable { document: Arc::new(Mutex::new(did_document(&secret(1)))), lookups: lookups.clone() };
+        let verifier = verifier(resolver);
+        let forged = Repo::new(&secret(2));
+
+        for _ in 0..100 {
+            assert!(verifier.verify(&forged.frame(PATH), &forged.blocks()).is_err());
+        }
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+
+        // Until an #identity event says the key may have changed
+        verifier.forget(DID);
+        assert!(verifier.verify(&forged.frame(PATH), &forged.blocks()).is_err());
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn missing_mst_path() {
+        let key = secret(1);
+        let verifier = verifier(fixture_resolver("mst", did_document(&key)));
+        let repo = Repo::new(&key);
+
+        // A create for a record the MST doesn't have
+        let error = verifier.verify(&repo.frame("app.bsky.feed.post/3kxyz"), &repo.blocks()).unwrap_err();
+        assert!(error.contains("doesn't match the MST"), "{}", error);
+
+        // An MST node missing from the frame can't prove anything either way
+        let mut blocks = repo.blocks();
+        blocks.remove(repo.blocks[1].0.as_bytes());
+        let error = verifier.verify(&repo.frame(PATH), &blocks).unwrap_err();
+        assert!(error.contains("missing from the frame"), "{}", error);
+    }
+}